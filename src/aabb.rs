@@ -24,11 +24,21 @@ impl Aabb {
     }
 
     pub fn hit(&self, ray: &Ray, interval: Interval) -> bool {
+        let inverse_direction = Vec3::new(
+            1.0 / ray.direction.x,
+            1.0 / ray.direction.y,
+            1.0 / ray.direction.z,
+        );
+        self.hit_inverse(ray.origin, inverse_direction, interval)
+    }
+
+    // same as hit, but the reciprocal of the direction is computed once per ray by the caller
+    pub fn hit_inverse(&self, origin: Vec3, inverse_direction: Vec3, interval: Interval) -> bool {
         let mut t_interval = interval;
         for i in 0..3 {
-            let inv_d = 1.0 / ray.direction[i];
-            let t0 = (self[i].min - ray.origin[i]) * inv_d;
-            let t1 = (self[i].max - ray.origin[i]) * inv_d;
+            let inv_d = inverse_direction[i];
+            let t0 = (self[i].min - origin[i]) * inv_d;
+            let t1 = (self[i].max - origin[i]) * inv_d;
             t_interval = t_interval.intersect(Interval::min_max(t0, t1));
             if t_interval.empty() {
                 return false;
@@ -49,6 +59,22 @@ impl Aabb {
         self.x.contains(v.x) && self.y.contains(v.y) && self.z.contains(v.z)
     }
 
+    pub fn empty(&self) -> bool {
+        self.x.empty() || self.y.empty() || self.z.empty()
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        if self.empty() {
+            return 0.0;
+        }
+        let (dx, dy, dz) = (self.x.length(), self.y.length(), self.z.length());
+        2.0 * (dx * dy + dy * dz + dz * dx)
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min_pos() + self.max_pos()) * 0.5
+    }
+
     pub fn longest_axis(&self) -> usize {
         if self.x.length() > self.y.length() {
             if self.x.length() > self.z.length() {
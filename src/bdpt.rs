@@ -0,0 +1,529 @@
+use std::f64::consts::PI;
+
+use crate::color::Color;
+use crate::hit_record::HitRecord;
+use crate::hittable::{Hittable, World};
+use crate::integrator::{Integrator, PathTracer};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+enum VertexKind {
+    Camera,
+    // a point on a light. its radiance is emission, spread like a diffuse surface
+    Light,
+    // the hit is kept to evaluate the material towards other vertices
+    Surface(Box<HitRecord>),
+}
+
+// a vertex of a camera or light subpath
+struct Vertex {
+    kind: VertexKind,
+    position: Vec3,
+    // faces the side the subpath arrived from. zero inside media, which need no cosine
+    normal: Vec3,
+    emission: Color,
+    // the throughput of the subpath up to this vertex
+    throughput: Color,
+    // the densities by area of sampling this vertex from the previous vertex of its subpath, and
+    // from the next one, i.e. when the path is sampled in the opposite direction
+    pdf_forward: f64,
+    pdf_reverse: f64,
+    // scattered by a delta lobe, e.g. a mirror or glass. no connection can end here
+    delta: bool,
+    // the density by area of sampling this point on the lights. zero if it isn't on a light
+    light_prob: f64,
+}
+
+impl Vertex {
+    fn new(kind: VertexKind, position: Vec3, normal: Vec3, throughput: Color) -> Self {
+        Self {
+            kind,
+            position,
+            normal,
+            emission: Color::BLACK,
+            throughput,
+            pdf_forward: 0.0,
+            pdf_reverse: 0.0,
+            delta: false,
+            light_prob: 0.0,
+        }
+    }
+
+    fn connectible(&self) -> bool {
+        match &self.kind {
+            VertexKind::Camera => false,
+            VertexKind::Light => true,
+            VertexKind::Surface(hit_record) => !hit_record.is_delta(),
+        }
+    }
+
+    // the bsdf (or emission) times the cosine towards the unit direction. only called on
+    // connectible vertices. adjoint on light subpaths
+    fn eval(&self, direction: Vec3, adjoint: bool) -> Color {
+        match &self.kind {
+            VertexKind::Surface(hit_record) if adjoint => {
+                hit_record.eval_scatter_adjoint(direction).0
+            }
+            VertexKind::Surface(hit_record) => hit_record.eval_scatter(direction).0,
+            _ => self.emission * self.normal.dot(direction).max(0.0),
+        }
+    }
+
+    // the density by solid angle of scattering towards the unit direction, having been reached
+    // the way the subpath came
+    fn prob(&self, direction: Vec3) -> f64 {
+        match &self.kind {
+            VertexKind::Surface(hit_record) => {
+                let outgoing = -hit_record.get_ray().direction.normalize();
+                hit_record.scatter_prob(outgoing, direction)
+            }
+            _ => (self.normal.dot(direction) / PI).max(0.0),
+        }
+    }
+
+    // the density by area of this vertex scattering towards next, having been reached from
+    // previous
+    fn pdf(&self, previous: &Vertex, next: &Vertex) -> f64 {
+        if self.delta || !self.connectible() {
+            return 0.0;
+        }
+        let direction = (next.position - self.position).normalize();
+        let prob = match &self.kind {
+            VertexKind::Surface(hit_record) => {
+                let outgoing = (previous.position - self.position).normalize();
+                hit_record.scatter_prob(outgoing, direction)
+            }
+            _ => self.prob(direction),
+        };
+        to_area(prob, self.position, next)
+    }
+}
+
+// where a subpath goes on from: the next ray, the throughput so far and the density by solid angle
+// of the last vertex choosing ray
+struct Walk {
+    ray: Ray,
+    throughput: Color,
+    pdf: f64,
+}
+
+// the camera and light subpaths of a sample, traced at the same time
+struct Subpaths {
+    camera: Vec<Vertex>,
+    light: Vec<Vertex>,
+    time: f64,
+}
+
+// convert a density by solid angle at from into one by area at the vertex it points to
+fn to_area(pdf: f64, from: Vec3, to: &Vertex) -> f64 {
+    let offset = to.position - from;
+    let distance_squared = offset.length_squared();
+    let cosine = if to.normal.length_squared() > 0.0 {
+        to.normal.dot(offset).abs() / distance_squared.sqrt()
+    } else {
+        1.0
+    };
+    pdf * cosine / distance_squared
+}
+
+// bidirectional path tracing. a path is traced from the camera and another from a point on the
+// lights, and every prefix of one is connected to every prefix of the other by a shadow ray. each
+// combination is weighted with the power heuristic against the others that sample the same path.
+// connections straight to the camera aren't made, since a sample only contributes to its pixel
+pub struct BidirectionalPathTracer {
+    max_depth: u32,
+    // bounces before russian roulette may end a subpath
+    roulette_depth: u32,
+}
+
+impl BidirectionalPathTracer {
+    pub fn new(max_depth: u32) -> Self {
+        Self {
+            max_depth,
+            roulette_depth: PathTracer::DEFAULT_ROULETTE_DEPTH,
+        }
+    }
+
+    pub fn set_roulette_depth(mut self, depth: u32) -> Self {
+        self.roulette_depth = depth;
+        self
+    }
+
+    // extend the subpath in vertices from walk until it has max_vertices. return the radiance of
+    // the background times the throughput if the subpath escapes
+    fn random_walk(
+        &self,
+        world: &World,
+        walk: Walk,
+        vertices: &mut Vec<Vertex>,
+        max_vertices: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let Walk {
+            mut ray,
+            mut throughput,
+            mut pdf,
+        } = walk;
+        // light subpaths carry light the other way, which asymmetric bsdfs care about
+        let adjoint = matches!(vertices[0].kind, VertexKind::Light);
+        while vertices.len() < max_vertices {
+            let mut hit_record = HitRecord::new(ray);
+            if !world.objects.hit(&mut hit_record, sampler) {
+                return throughput * world.background(hit_record.get_ray());
+            }
+            let hit = hit_record.get_hit();
+            let (position, normal, emission) = (hit.position, hit.normal, hit.emission);
+            let light_prob = if emission.max_component() > 0.0 && !world.light_pdf.empty() {
+                world
+                    .light_pdf
+                    .area_prob(hit_record.get_ray(), hit.t, sampler)
+            } else {
+                0.0
+            };
+            // the densities of sampling the next vertex from this one, and the previous one.
+            // delta lobes have none
+            let sample = if adjoint {
+                hit_record.sample_scatter_adjoint(sampler)
+            } else {
+                hit_record.sample_scatter(sampler)
+            };
+            let (delta, next_pdf, reverse_pdf) = match &sample {
+                Some((next, _, Some(prob))) => (
+                    false,
+                    *prob,
+                    hit_record.reverse_scatter_prob(next.direction.normalize()),
+                ),
+                Some((_, _, None)) => (true, 0.0, 0.0),
+                None => (false, 0.0, 0.0),
+            };
+            let mut vertex = Vertex::new(
+                VertexKind::Surface(Box::new(hit_record)),
+                position,
+                normal,
+                throughput,
+            );
+            vertex.emission = emission;
+            vertex.delta = delta;
+            vertex.light_prob = light_prob;
+            let previous = vertices.last_mut().unwrap();
+            vertex.pdf_forward = to_area(pdf, previous.position, &vertex);
+            previous.pdf_reverse = to_area(reverse_pdf, position, previous);
+            vertices.push(vertex);
+            let Some((next, attenuation, _)) = sample else {
+                break;
+            };
+            throughput = throughput * attenuation;
+            if vertices.len() > self.roulette_depth as usize + 1 {
+                let probability = throughput.max_component().min(0.95);
+                if sampler.get_1d() >= probability {
+                    break;
+                }
+                throughput /= probability;
+            }
+            ray = next;
+            pdf = next_pdf;
+        }
+        Color::BLACK
+    }
+
+    // start at a point on the lights and follow the emitted light
+    fn light_path(&self, world: &World, time: f64, sampler: &mut dyn Sampler) -> Vec<Vertex> {
+        let mut vertices = Vec::new();
+        let Some((position, normal, emission, position_prob)) = world.sample_emitter(time, sampler)
+        else {
+            return vertices;
+        };
+        let mut vertex = Vertex::new(
+            VertexKind::Light,
+            position,
+            normal,
+            Color::WHITE / position_prob,
+        );
+        vertex.emission = emission;
+        vertex.pdf_forward = position_prob;
+        vertex.light_prob = position_prob;
+        vertices.push(vertex);
+        // emit with a cosine distribution, which cancels with the cosine of the emission
+        let direction = Onb::normal(normal).local(Vec3::random_cosine_direction(sampler));
+        let walk = Walk {
+            ray: Ray::with_time(position, direction, time),
+            throughput: emission * (PI / position_prob),
+            pdf: normal.dot(direction) / PI,
+        };
+        self.random_walk(world, walk, &mut vertices, self.max_depth as usize, sampler);
+        vertices
+    }
+
+    // the contribution of the path made of the first s vertices of the light subpath and the first
+    // t vertices of the camera subpath
+    fn connect(
+        &self,
+        world: &World,
+        paths: &Subpaths,
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let (camera, light) = (&paths.camera, &paths.light);
+        let pt = &camera[t - 1];
+        if s == 0 {
+            // the camera subpath reached an emitter by itself
+            if pt.emission.max_component() <= 0.0 {
+                return Color::BLACK;
+            }
+            // emitters that aren't lights can't be reached any other way
+            if pt.light_prob <= 0.0 {
+                return pt.throughput * pt.emission;
+            }
+            return pt.throughput * pt.emission * self.weight(paths, s, t);
+        }
+        let qs = &light[s - 1];
+        if !pt.connectible() || !qs.connectible() {
+            return Color::BLACK;
+        }
+        let offset = qs.position - pt.position;
+        let distance_squared = offset.length_squared();
+        let direction = offset / distance_squared.sqrt();
+        let pt_value = pt.eval(direction, false);
+        let qs_value = qs.eval(-direction, true);
+        let contribution = pt.throughput * pt_value * qs_value * qs.throughput / distance_squared;
+        if contribution.max_component() <= 0.0 {
+            return Color::BLACK;
+        }
+        if world.occluded(
+            &Ray::with_time(pt.position, direction, paths.time),
+            qs.position,
+            sampler,
+        ) {
+            return Color::BLACK;
+        }
+        contribution * self.weight(paths, s, t)
+    }
+
+    // the power heuristic weight of the (s, t) strategy against the others that sample the same
+    // path. the reverse densities of the connected vertices and of the vertices before them depend
+    // on the connection, so they are found first
+    fn weight(&self, paths: &Subpaths, s: usize, t: usize) -> f64 {
+        let (camera, light) = (&paths.camera, &paths.light);
+        let pt = &camera[t - 1];
+        let pt_minus = &camera[t - 2];
+        let (camera_reverse, light_reverse) = if s == 0 {
+            // pt is on a light, reached without connecting
+            let direction = (pt_minus.position - pt.position).normalize();
+            let emit_prob = (pt.normal.dot(direction) / PI).max(0.0);
+            let reverse = [pt.light_prob, to_area(emit_prob, pt.position, pt_minus)];
+            (reverse, [0.0; 2])
+        } else {
+            let qs = &light[s - 1];
+            let direction = (qs.position - pt.position).normalize();
+            let camera_reverse = [
+                to_area(qs.prob(-direction), qs.position, pt),
+                pt.pdf(qs, pt_minus),
+            ];
+            let light_reverse = [
+                to_area(pt.prob(direction), pt.position, qs),
+                if s > 1 {
+                    qs.pdf(pt, &light[s - 2])
+                } else {
+                    0.0
+                },
+            ];
+            (camera_reverse, light_reverse)
+        };
+        // delta vertices have zero densities that cancel out
+        let remap = |pdf: f64| if pdf == 0.0 { 1.0 } else { pdf };
+        let mut sum = 0.0;
+        // move the connection towards the camera, without reaching it
+        let mut ratio = 1.0;
+        for i in (2..t).rev() {
+            let reverse = match t - 1 - i {
+                0 => camera_reverse[0],
+                1 => camera_reverse[1],
+                _ => camera[i].pdf_reverse,
+            };
+            ratio *= remap(reverse) / remap(camera[i].pdf_forward);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum += ratio * ratio;
+            }
+        }
+        // move the connection towards the light
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            let reverse = match s - 1 - i {
+                0 => light_reverse[0],
+                1 => light_reverse[1],
+                _ => light[i].pdf_reverse,
+            };
+            ratio *= remap(reverse) / remap(light[i].pdf_forward);
+            if !light[i].delta && (i == 0 || !light[i - 1].delta) {
+                sum += ratio * ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn radiance(&self, world: &World, ray: Ray, sampler: &mut dyn Sampler) -> Color {
+        let time = ray.time;
+        let mut camera = vec![Vertex::new(
+            VertexKind::Camera,
+            ray.origin,
+            Vec3::default(),
+            Color::WHITE,
+        )];
+        // escaping is only sampled by the camera subpath, so it needs no weight
+        let walk = Walk {
+            ray,
+            throughput: Color::WHITE,
+            pdf: 0.0,
+        };
+        let mut radiance = self.random_walk(
+            world,
+            walk,
+            &mut camera,
+            self.max_depth as usize + 1,
+            sampler,
+        );
+        let paths = Subpaths {
+            camera,
+            light: self.light_path(world, time, sampler),
+            time,
+        };
+        for t in 2..=paths.camera.len() {
+            for s in 0..=paths.light.len() {
+                if s + t - 1 > self.max_depth as usize {
+                    break;
+                }
+                radiance += self.connect(world, &paths, s, t, sampler);
+            }
+        }
+        radiance
+    }
+}
+
+#[cfg(test)]
+fn lit_corner() -> World {
+    use crate::hittable::lit_box;
+    use crate::material::Lambertian;
+    use crate::shape::Quad;
+    use crate::texture::Atlas;
+
+    // a wall at x = -2 facing the light, for light that bounces more than once
+    let mut builder = lit_box(true);
+    let wall = Quad::new(
+        Vec3::new(-2.0, 0.0, -2.0),
+        Vec3::new(0.0, 2.0, 0.0),
+        Vec3::new(0.0, 0.0, 4.0),
+    );
+    builder.add_object(wall, Lambertian, Atlas::default());
+    builder.build()
+}
+
+#[test]
+fn test_bidirectional() {
+    use crate::sampler::IndependentSampler;
+
+    let world = lit_corner();
+    let ray = || Ray::new(Vec3::new(0.0, 0.5, 1.0), Vec3::new(-0.5, -0.5, -1.0));
+    let mean = |integrator: &dyn Integrator, samples: u32| {
+        let mut sampler = IndependentSampler::new(0);
+        let mut sum = Color::BLACK;
+        for _ in 0..samples {
+            sum += integrator.radiance(&world, ray(), &mut sampler);
+        }
+        sum.g / samples as f64
+    };
+    let bidirectional = mean(&BidirectionalPathTracer::new(4), 20000);
+    let path = mean(&PathTracer::new(4), 40000);
+    assert!(path > 0.1);
+    assert!((bidirectional / path - 1.0).abs() < 0.03);
+}
+
+#[test]
+fn test_weights() {
+    use crate::sampler::IndependentSampler;
+
+    // the subpath from first through points, with the densities random_walk gives it. pdf is the
+    // density by solid angle of first choosing the direction to the first point
+    fn subpath(
+        world: &World,
+        first: Vertex,
+        mut pdf: f64,
+        points: &[Vec3],
+        sampler: &mut dyn Sampler,
+    ) -> Vec<Vertex> {
+        let mut vertices = vec![first];
+        for (i, &point) in points.iter().enumerate() {
+            let from = vertices.last().unwrap().position;
+            let mut hit_record = HitRecord::new(Ray::new(from, point - from));
+            assert!(world.objects.hit(&mut hit_record, sampler));
+            let hit = hit_record.get_hit();
+            assert!((hit.position - point).length() < 1e-9);
+            let (position, normal, emission) = (hit.position, hit.normal, hit.emission);
+            let light_prob = if emission.max_component() > 0.0 {
+                world
+                    .light_pdf
+                    .area_prob(hit_record.get_ray(), hit.t, sampler)
+            } else {
+                0.0
+            };
+            let (next_pdf, reverse_pdf) = match points.get(i + 1) {
+                Some(&next) => {
+                    let direction = (next - position).normalize();
+                    let outgoing = (from - position).normalize();
+                    (
+                        hit_record.scatter_prob(outgoing, direction),
+                        hit_record.reverse_scatter_prob(direction),
+                    )
+                }
+                None => (0.0, 0.0),
+            };
+            let mut vertex = Vertex::new(
+                VertexKind::Surface(Box::new(hit_record)),
+                position,
+                normal,
+                Color::WHITE,
+            );
+            vertex.emission = emission;
+            vertex.light_prob = light_prob;
+            let previous = vertices.last_mut().unwrap();
+            vertex.pdf_forward = to_area(pdf, previous.position, &vertex);
+            previous.pdf_reverse = to_area(reverse_pdf, position, previous);
+            vertices.push(vertex);
+            pdf = next_pdf;
+        }
+        vertices
+    }
+
+    // camera, floor, wall and light. each strategy that can make this path samples it with some
+    // density, and the weights split it among them
+    let world = lit_corner();
+    let mut sampler = IndependentSampler::new(0);
+    let eye = Vec3::new(0.0, 0.5, 1.0);
+    let floor = Vec3::new(0.0, 0.0, 0.0);
+    let wall = Vec3::new(-2.0, 1.0, 0.0);
+    let light = Vec3::new(0.3, 2.0, 0.2);
+    let camera = Vertex::new(VertexKind::Camera, eye, Vec3::default(), Color::WHITE);
+    let camera = subpath(&world, camera, 0.0, &[floor, wall, light], &mut sampler);
+    let normal = Vec3::new(0.0, -1.0, 0.0);
+    let mut emitter = Vertex::new(VertexKind::Light, light, normal, Color::WHITE);
+    emitter.emission = Color::WHITE * 4.0;
+    emitter.light_prob = camera[3].light_prob;
+    emitter.pdf_forward = emitter.light_prob;
+    let pdf = emitter.prob((wall - light).normalize());
+    let light = subpath(&world, emitter, pdf, &[wall, floor], &mut sampler);
+    let paths = Subpaths {
+        camera,
+        light,
+        time: 0.0,
+    };
+    let integrator = BidirectionalPathTracer::new(4);
+    let weights: Vec<f64> = (0..=2)
+        .map(|s| integrator.weight(&paths, s, 4 - s))
+        .collect();
+    assert!(weights.iter().all(|&weight| weight > 0.0));
+    assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+}
@@ -0,0 +1,623 @@
+use std::f64::consts::PI;
+use std::fmt::Debug;
+
+use crate::color::Color;
+use crate::microfacet::{fresnel_conductor, fresnel_dielectric, GGX};
+use crate::onb::Onb;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// a direction sampled from a bsdf
+#[derive(Clone, Copy, Debug)]
+pub struct BSDFSample {
+    // normalized
+    pub direction: Vec3,
+    // the bsdf times the cosine, divided by the probability. what the throughput is multiplied by
+    pub weight: Color,
+    // the probability density by solid angle. None if a delta lobe was sampled
+    pub prob: Option<f64>,
+}
+
+// how a material scatters light at a hit. all directions are normalized and point away from the
+// hit: outgoing towards where the light leaves to (e.g. the camera), incoming towards where it
+// comes from
+pub trait BSDF: Debug {
+    // the bsdf times the absolute cosine of incoming. zero for delta lobes
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color;
+    // the probability density by solid angle of sample choosing incoming. zero for delta lobes
+    fn prob(&self, outgoing: Vec3, incoming: Vec3) -> f64;
+    // None if the light is absorbed
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample>;
+    // whether every lobe is a delta, so that eval and prob are always zero
+    fn is_delta(&self) -> bool {
+        false
+    }
+    // eval for light travelling the other way, arriving from outgoing and leaving towards
+    // incoming, which paths traced from the lights need. the same unless the bsdf isn't symmetric
+    fn eval_adjoint(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        self.eval(outgoing, incoming)
+    }
+}
+
+impl<T: BSDF + ?Sized> BSDF for Box<T> {
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        self.as_ref().eval(outgoing, incoming)
+    }
+
+    fn prob(&self, outgoing: Vec3, incoming: Vec3) -> f64 {
+        self.as_ref().prob(outgoing, incoming)
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        self.as_ref().sample(outgoing, sampler)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.as_ref().is_delta()
+    }
+
+    fn eval_adjoint(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        self.as_ref().eval_adjoint(outgoing, incoming)
+    }
+}
+
+#[derive(Debug)]
+pub struct LambertianBSDF {
+    albedo: Color,
+    uvw: Onb,
+}
+
+impl LambertianBSDF {
+    pub fn new(albedo: Color, normal: Vec3) -> Self {
+        Self {
+            albedo,
+            uvw: Onb::normal(normal),
+        }
+    }
+}
+
+impl BSDF for LambertianBSDF {
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        self.albedo * self.prob(outgoing, incoming)
+    }
+
+    fn prob(&self, _outgoing: Vec3, incoming: Vec3) -> f64 {
+        (incoming.dot(self.uvw.w) / PI).max(0.0)
+    }
+
+    fn sample(&self, _outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let direction = self.uvw.local(Vec3::random_cosine_direction(sampler));
+        Some(BSDFSample {
+            direction,
+            weight: self.albedo,
+            prob: Some((direction.dot(self.uvw.w) / PI).max(0.0)),
+        })
+    }
+}
+
+// reflects about the normal, then moves the direction by up to fuzz in a uniformly random
+// direction. directions that end up below the surface are absorbed. a delta lobe without fuzz
+#[derive(Debug)]
+pub struct MetalBSDF {
+    albedo: Color,
+    normal: Vec3,
+    fuzz: f64,
+}
+
+impl MetalBSDF {
+    pub fn new(albedo: Color, normal: Vec3, fuzz: f64) -> Self {
+        Self {
+            albedo,
+            normal,
+            fuzz,
+        }
+    }
+}
+
+impl BSDF for MetalBSDF {
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        // sampling is exact, so the weight of any sample is the albedo
+        self.albedo * self.prob(outgoing, incoming)
+    }
+
+    // the reflected direction plus the fuzz is uniform on a sphere of radius fuzz around the
+    // reflection. every point where the ray along incoming crosses that sphere adds the density
+    // of the point, t^2 / (4 pi fuzz^2 cos), to the density of the direction
+    fn prob(&self, outgoing: Vec3, incoming: Vec3) -> f64 {
+        if self.is_delta() || incoming.dot(self.normal) <= 0.0 {
+            return 0.0;
+        }
+        let reflected = (-outgoing).reflect(self.normal);
+        let b = incoming.dot(reflected);
+        let discriminant = b * b - 1.0 + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let root = discriminant.sqrt();
+        [b - root, b + root]
+            .into_iter()
+            .filter(|&t| t > 0.0)
+            .map(|t| t * t / (4.0 * PI * self.fuzz * root))
+            .sum()
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let reflected = (-outgoing).reflect(self.normal);
+        let direction = reflected + Vec3::random_unit_vector(sampler) * self.fuzz;
+        if direction.dot(self.normal) <= 0.0 {
+            return None;
+        }
+        let direction = direction.normalize();
+        Some(BSDFSample {
+            direction,
+            weight: self.albedo,
+            prob: (!self.is_delta()).then(|| self.prob(outgoing, direction)),
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        self.fuzz <= 0.0
+    }
+}
+
+// a metal with a microfacet surface. smooth conductors are a delta lobe
+#[derive(Debug)]
+pub struct ConductorBSDF {
+    uvw: Onb,
+    ggx: GGX,
+    eta: Color,
+    k: Color,
+}
+
+impl ConductorBSDF {
+    pub fn new(normal: Vec3, ggx: GGX, eta: Color, k: Color) -> Self {
+        Self {
+            uvw: Onb::normal(normal),
+            ggx,
+            eta,
+            k,
+        }
+    }
+}
+
+impl BSDF for ConductorBSDF {
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        let (wo, wi) = (self.uvw.to_local(outgoing), self.uvw.to_local(incoming));
+        if self.ggx.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return Color::BLACK;
+        }
+        let h = (wo + wi).normalize();
+        let fresnel = fresnel_conductor(wo.dot(h), self.eta, self.k);
+        // d g f / (4 cos_o cos_i), times cos_i
+        fresnel * (self.ggx.d(h) * self.ggx.g(wo, wi) / (4.0 * wo.z))
+    }
+
+    fn prob(&self, outgoing: Vec3, incoming: Vec3) -> f64 {
+        let (wo, wi) = (self.uvw.to_local(outgoing), self.uvw.to_local(incoming));
+        if self.ggx.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).normalize();
+        // the jacobian of reflecting about h
+        self.ggx.visible_prob(wo, h) / (4.0 * wo.dot(h))
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let wo = self.uvw.to_local(outgoing);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        if self.ggx.is_smooth() {
+            return Some(BSDFSample {
+                direction: self.uvw.local(Vec3::new(-wo.x, -wo.y, wo.z)),
+                weight: fresnel_conductor(wo.z, self.eta, self.k),
+                prob: None,
+            });
+        }
+        let h = self.ggx.sample_visible(wo, sampler);
+        let wi = h * (2.0 * wo.dot(h)) - wo;
+        if wi.z <= 0.0 {
+            return None;
+        }
+        // sampling the visible normals leaves the masking of the incoming direction
+        let masking = self.ggx.g(wo, wi) / self.ggx.g1(wo);
+        Some(BSDFSample {
+            direction: self.uvw.local(wi),
+            weight: fresnel_conductor(wo.dot(h), self.eta, self.k) * masking,
+            prob: Some(self.ggx.visible_prob(wo, h) / (4.0 * wo.dot(h))),
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        self.ggx.is_smooth()
+    }
+}
+
+fn refract(direction: Vec3, normal: Vec3, cos_theta: f64, refraction_ratio: f64) -> Vec3 {
+    let r_out_perp = (direction + normal * cos_theta) * refraction_ratio;
+    let r_out_parallel = -normal * (1.0 - r_out_perp.length_squared()).sqrt();
+    r_out_perp + r_out_parallel
+}
+
+// smooth glass. reflects or refracts with the probability given by the fresnel equations
+#[derive(Debug)]
+pub struct DielectricBSDF {
+    normal: Vec3,
+    // the index of refraction of the side the light leaves to over that of the other side
+    refraction_ratio: f64,
+}
+
+impl DielectricBSDF {
+    pub fn new(normal: Vec3, refraction_ratio: f64) -> Self {
+        Self {
+            normal,
+            refraction_ratio,
+        }
+    }
+
+    // schlick's approximation
+    pub fn reflectance(cosine: f64, refraction_ratio: f64) -> f64 {
+        let r0 = ((1.0 - refraction_ratio) / (1.0 + refraction_ratio)).powi(2);
+        r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+    }
+}
+
+impl BSDF for DielectricBSDF {
+    fn eval(&self, _outgoing: Vec3, _incoming: Vec3) -> Color {
+        Color::BLACK
+    }
+
+    fn prob(&self, _outgoing: Vec3, _incoming: Vec3) -> f64 {
+        0.0
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let cos_theta = outgoing.dot(self.normal);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let direction = if self.refraction_ratio * sin_theta > 1.0
+            || Self::reflectance(cos_theta, self.refraction_ratio) > sampler.get_1d()
+        {
+            (-outgoing).reflect(self.normal)
+        } else {
+            refract(-outgoing, self.normal, cos_theta, self.refraction_ratio)
+        };
+        Some(BSDFSample {
+            direction,
+            weight: Color::WHITE,
+            prob: None,
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+// glass with a microfacet surface, e.g. frosted or sandblasted. each microfacet reflects or
+// refracts like smooth glass, with the exact fresnel equations. smooth surfaces are delta lobes.
+// as with DielectricBSDF, radiance isn't scaled by the change of index when refracted
+#[derive(Debug)]
+pub struct RoughDielectricBSDF {
+    uvw: Onb,
+    ggx: GGX,
+    // the index of refraction of the other side over that of the side the light leaves to
+    eta: f64,
+}
+
+impl RoughDielectricBSDF {
+    pub fn new(normal: Vec3, ggx: GGX, eta: f64) -> Self {
+        Self {
+            uvw: Onb::normal(normal),
+            ggx,
+            eta,
+        }
+    }
+
+    // both directions in the local frame, flipped if outgoing is below the surface, and eta for
+    // that side. paths sampled in the opposite direction ask for those
+    fn local(&self, outgoing: Vec3, incoming: Vec3) -> (Vec3, Vec3, f64) {
+        let (wo, wi) = (self.uvw.to_local(outgoing), self.uvw.to_local(incoming));
+        if wo.z < 0.0 {
+            let flip = |w: Vec3| Vec3::new(w.x, w.y, -w.z);
+            (flip(wo), flip(wi), 1.0 / self.eta)
+        } else {
+            (wo, wi, self.eta)
+        }
+    }
+
+    // the microfacet normal that turns wo into wi, facing wo. None if there is none
+    fn half_vector(wo: Vec3, wi: Vec3, eta: f64) -> Option<Vec3> {
+        let h = if wi.z > 0.0 { wo + wi } else { wo + wi * eta };
+        if h.length_squared() <= 0.0 {
+            return None;
+        }
+        let h = h.normalize();
+        let h = if h.z < 0.0 { -h } else { h };
+        // refraction crosses the microfacet, reflection doesn't
+        let valid = wo.dot(h) > 0.0 && (wi.z > 0.0) == (wi.dot(h) > 0.0);
+        valid.then_some(h)
+    }
+
+    // the density of choosing wi after sampling h: the choice between reflection and refraction,
+    // and the jacobian of turning h into wi
+    fn prob_with(&self, wo: Vec3, wi: Vec3, h: Vec3, eta: f64, fresnel: f64) -> f64 {
+        let prob = self.ggx.visible_prob(wo, h);
+        if wi.z > 0.0 {
+            prob * fresnel / (4.0 * wo.dot(h))
+        } else {
+            let denominator = wo.dot(h) + eta * wi.dot(h);
+            prob * (1.0 - fresnel) * eta * eta * wi.dot(h).abs() / (denominator * denominator)
+        }
+    }
+}
+
+impl BSDF for RoughDielectricBSDF {
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        let (wo, wi, eta) = self.local(outgoing, incoming);
+        if self.ggx.is_smooth() || wo.z == 0.0 || wi.z == 0.0 {
+            return Color::BLACK;
+        }
+        let Some(h) = Self::half_vector(wo, wi, eta) else {
+            return Color::BLACK;
+        };
+        let fresnel = fresnel_dielectric(wo.dot(h), eta);
+        let dg = self.ggx.d(h) * self.ggx.g(wo, wi);
+        // times the cosine of wi
+        let value = if wi.z > 0.0 {
+            dg * fresnel / (4.0 * wo.z)
+        } else {
+            let denominator = wo.dot(h) + eta * wi.dot(h);
+            dg * (1.0 - fresnel) * eta * eta * wi.dot(h).abs() * wo.dot(h)
+                / (wo.z * denominator * denominator)
+        };
+        Color::gray(value)
+    }
+
+    fn prob(&self, outgoing: Vec3, incoming: Vec3) -> f64 {
+        let (wo, wi, eta) = self.local(outgoing, incoming);
+        if self.ggx.is_smooth() || wo.z == 0.0 || wi.z == 0.0 {
+            return 0.0;
+        }
+        let Some(h) = Self::half_vector(wo, wi, eta) else {
+            return 0.0;
+        };
+        self.prob_with(wo, wi, h, eta, fresnel_dielectric(wo.dot(h), eta))
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let wo = self.uvw.to_local(outgoing);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let smooth = self.ggx.is_smooth();
+        let h = if smooth {
+            Vec3::new(0.0, 0.0, 1.0)
+        } else {
+            self.ggx.sample_visible(wo, sampler)
+        };
+        let cos_o = wo.dot(h);
+        let fresnel = fresnel_dielectric(cos_o, self.eta);
+        let wi = if sampler.get_1d() < fresnel {
+            h * (2.0 * cos_o) - wo
+        } else {
+            let cos_t = (1.0 - (1.0 - cos_o * cos_o) / (self.eta * self.eta)).sqrt();
+            -wo / self.eta + h * (cos_o / self.eta - cos_t)
+        };
+        // the microfacet sent it to the wrong side of the surface
+        if wi.z == 0.0 || (wi.z > 0.0) != (wi.dot(h) > 0.0) {
+            return None;
+        }
+        if smooth {
+            return Some(BSDFSample {
+                direction: self.uvw.local(wi),
+                weight: Color::WHITE,
+                prob: None,
+            });
+        }
+        // sampling the visible normals leaves the masking of the incoming direction
+        let masking = self.ggx.g(wo, wi) / self.ggx.g1(wo);
+        Some(BSDFSample {
+            direction: self.uvw.local(wi).normalize(),
+            weight: Color::gray(masking),
+            prob: Some(self.prob_with(wo, wi, h, self.eta, fresnel)),
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        self.ggx.is_smooth()
+    }
+
+    // refraction squeezes the light into a narrower cone, so going the other way differs by the
+    // square of eta. smooth refraction isn't corrected, since entering and leaving cancel out
+    fn eval_adjoint(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        let (_, wi, eta) = self.local(outgoing, incoming);
+        let value = self.eval(outgoing, incoming);
+        if wi.z < 0.0 {
+            value / (eta * eta)
+        } else {
+            value
+        }
+    }
+}
+
+// glass that tints what it reflects and refracts by the angle. reflection and refraction are
+// equally likely
+#[derive(Debug)]
+pub struct TranslucentBSDF {
+    normal: Vec3,
+    refraction_ratio: f64,
+}
+
+impl TranslucentBSDF {
+    pub fn new(normal: Vec3, refraction_ratio: f64) -> Self {
+        Self {
+            normal,
+            refraction_ratio,
+        }
+    }
+}
+
+impl BSDF for TranslucentBSDF {
+    fn eval(&self, _outgoing: Vec3, _incoming: Vec3) -> Color {
+        Color::BLACK
+    }
+
+    fn prob(&self, _outgoing: Vec3, _incoming: Vec3) -> f64 {
+        0.0
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let cos_theta = outgoing.dot(self.normal);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let mut direction = (-outgoing).reflect(self.normal);
+        let mut weight = Color::WHITE;
+        if self.refraction_ratio * sin_theta <= 1.0 {
+            let reflect_color = Color::new(1.0, sin_theta, 0.0);
+            if sampler.get_1d() < 0.5 {
+                direction = refract(-outgoing, self.normal, cos_theta, self.refraction_ratio);
+                weight = (Color::WHITE - reflect_color) * 2.0;
+            } else {
+                weight = reflect_color * 2.0;
+            }
+        }
+        Some(BSDFSample {
+            direction,
+            weight,
+            prob: None,
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+// the phase function of a medium, which scatters equally in all directions
+#[derive(Debug)]
+pub struct IsotropicBSDF {
+    albedo: Color,
+}
+
+impl IsotropicBSDF {
+    pub fn new(albedo: Color) -> Self {
+        Self { albedo }
+    }
+}
+
+impl BSDF for IsotropicBSDF {
+    fn eval(&self, _outgoing: Vec3, _incoming: Vec3) -> Color {
+        self.albedo / (4.0 * PI)
+    }
+
+    fn prob(&self, _outgoing: Vec3, _incoming: Vec3) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+
+    fn sample(&self, _outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        Some(BSDFSample {
+            direction: Vec3::random_unit_vector(sampler),
+            weight: self.albedo,
+            prob: Some(1.0 / (4.0 * PI)),
+        })
+    }
+}
+
+// another bsdf with all it scatters multiplied by tint, e.g. what is left after absorption
+#[derive(Debug)]
+pub struct TintedBSDF {
+    bsdf: Box<dyn BSDF>,
+    tint: Color,
+}
+
+impl TintedBSDF {
+    pub fn new(bsdf: Box<dyn BSDF>, tint: Color) -> Self {
+        Self { bsdf, tint }
+    }
+}
+
+impl BSDF for TintedBSDF {
+    fn eval(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        self.bsdf.eval(outgoing, incoming) * self.tint
+    }
+
+    fn prob(&self, outgoing: Vec3, incoming: Vec3) -> f64 {
+        self.bsdf.prob(outgoing, incoming)
+    }
+
+    fn sample(&self, outgoing: Vec3, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let sample = self.bsdf.sample(outgoing, sampler)?;
+        Some(BSDFSample {
+            weight: sample.weight * self.tint,
+            ..sample
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        self.bsdf.is_delta()
+    }
+
+    fn eval_adjoint(&self, outgoing: Vec3, incoming: Vec3) -> Color {
+        self.bsdf.eval_adjoint(outgoing, incoming) * self.tint
+    }
+}
+
+#[test]
+fn test_metal_prob() {
+    use crate::sampler::IndependentSampler;
+
+    // the density integrates to the share of samples that aren't absorbed. seen straight on, it
+    // only depends on the angle to the normal
+    let mut sampler = IndependentSampler::new(0);
+    let normal = Vec3::new(0.0, 0.0, 1.0);
+    for fuzz in [0.3, 1.0, 1.5] {
+        let bsdf = MetalBSDF::new(Color::WHITE, normal, fuzz);
+        let steps = 1000000;
+        let mut integral = 0.0;
+        for i in 0..steps {
+            let theta = (i as f64 + 0.5) / steps as f64 * PI;
+            let direction = Vec3::new(theta.sin(), 0.0, theta.cos());
+            integral += bsdf.prob(normal, direction) * 2.0 * PI * theta.sin() * PI / steps as f64;
+        }
+        let n = 100000;
+        let kept = (0..n)
+            .filter(|_| bsdf.sample(normal, &mut sampler).is_some())
+            .count() as f64
+            / n as f64;
+        assert!(
+            (integral - kept).abs() < 0.01,
+            "{} {} {}",
+            fuzz,
+            integral,
+            kept
+        );
+    }
+}
+
+#[test]
+fn test_microfacet_sample() {
+    use crate::sampler::IndependentSampler;
+
+    // the weight of a sample is the bsdf over its density
+    let mut sampler = IndependentSampler::new(0);
+    let normal = Vec3::new(0.0, 1.0, 0.0);
+    let outgoing = Vec3::new(0.0, 0.8, 0.6);
+    let eta = Color::new(0.143, 0.374, 1.442);
+    let k = Color::new(3.983, 2.385, 1.603);
+    let bsdfs: [Box<dyn BSDF>; 3] = [
+        Box::new(ConductorBSDF::new(normal, GGX::new(0.4), eta, k)),
+        Box::new(RoughDielectricBSDF::new(normal, GGX::new(0.4), 1.5)),
+        Box::new(RoughDielectricBSDF::new(normal, GGX::new(0.4), 1.0 / 1.5)),
+    ];
+    for bsdf in bsdfs {
+        for _ in 0..1000 {
+            let Some(sample) = bsdf.sample(outgoing, &mut sampler) else {
+                continue;
+            };
+            let prob = sample.prob.unwrap();
+            assert!((prob - bsdf.prob(outgoing, sample.direction)).abs() < 1e-9 * prob.max(1.0));
+            let expected = bsdf.eval(outgoing, sample.direction) / prob;
+            let error = expected - sample.weight;
+            assert!(error.r.abs().max(error.g.abs()).max(error.b.abs()) < 1e-9);
+        }
+    }
+}
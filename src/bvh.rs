@@ -1,13 +1,17 @@
+use std::str::FromStr;
+
 use crate::aabb::Aabb;
 use crate::hit_record::HitRecord;
 use crate::hittable::Hittable;
+use crate::sampler::Sampler;
 use crate::shape::Shape;
 use crate::texture::Atlas;
+use crate::vec3::Vec3;
 
 pub struct EmptyHittable;
 
 impl Hittable for EmptyHittable {
-    fn hit(&self, _hit_record: &mut HitRecord) -> bool {
+    fn hit(&self, _hit_record: &mut HitRecord, _sampler: &mut dyn Sampler) -> bool {
         false
     }
 
@@ -16,52 +20,299 @@ impl Hittable for EmptyHittable {
     }
 }
 
-pub struct HittableTree {
-    // left and right are the two children of the node.
-    // left isn't necessarily smaller than right, but each child is concentrated on one side of the parent for aabb pruning
-    left: Box<dyn Hittable>,
-    right: Box<dyn Hittable>,
-    // the bounding box of the node. cached for performance
-    aabb: Aabb,
+// how the primitives of a node are divided between its two children
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SplitStrategy {
+    // sort by centroid along the longest axis and split in half
+    Median,
+    // binned surface area heuristic. also decides when a leaf is cheaper than a split
+    #[default]
+    Sah,
+}
+
+impl FromStr for SplitStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "median" => Ok(SplitStrategy::Median),
+            "sah" => Ok(SplitStrategy::Sah),
+            _ => Err(format!("unknown split strategy \"{}\"", s)),
+        }
+    }
+}
+
+type Items<T> = Vec<(Aabb, T)>;
+
+const MAX_LEAF_SIZE: usize = 4;
+const BIN_COUNT: usize = 16;
+// cost of visiting a node relative to intersecting a primitive. testing a box isn't much cheaper
+// than testing a triangle through dynamic dispatch
+const TRAVERSAL_COST: f64 = 1.0;
+
+// traversal keeps the nodes still to visit on a fixed stack, so the build bounds the depth
+const MAX_DEPTH: usize = 64;
+// past this depth SAH falls back to median splits, which add at most log2(n) more levels
+const MAX_SAH_DEPTH: usize = 32;
+
+#[derive(Clone, Copy)]
+enum LinearNode {
+    // the items of a leaf are stored contiguously at [first, first + count)
+    Leaf { first: usize, count: usize },
+    // the first child directly follows its parent, the second one is at index second
+    Inner { second: usize, axis: usize },
+}
+
+// bounding volume hierarchy shared by HittableTree and ShapeTree. nodes are stored depth first in a
+// flat vector and items in leaf order, so traversal walks contiguous memory
+pub struct Bvh<T> {
+    // bounding boxes are kept apart from the nodes so that the traversal loop stays compact
+    aabbs: Vec<Aabb>,
+    nodes: Vec<LinearNode>,
+    items: Vec<T>,
 }
 
-impl HittableTree {
-    // take a list of AabbProvider and build a BVH tree
-    pub fn new(mut aabb_provider_list: Vec<Box<dyn Hittable>>) -> Self {
-        let aabb = aabb_provider_list
+impl<T> Bvh<T> {
+    // items are paired with their bounding boxes, which are computed only once
+    pub fn new(items: Items<T>, strategy: SplitStrategy) -> Self {
+        let mut bvh = Self {
+            aabbs: Vec::with_capacity(items.len() * 2),
+            nodes: Vec::with_capacity(items.len() * 2),
+            items: Vec::with_capacity(items.len()),
+        };
+        bvh.build(items, strategy, 0);
+        bvh
+    }
+
+    // append the subtree of items in depth-first order and return the index of its root
+    fn build(&mut self, items: Items<T>, strategy: SplitStrategy, depth: usize) -> usize {
+        let index = self.nodes.len();
+        let aabb = items
             .iter()
-            .fold(Aabb::default(), |acc, aabb_provider| {
-                acc.union(aabb_provider.bounding_box())
-            });
-        if aabb_provider_list.len() <= 2 {
-            return HittableTree {
-                aabb,
-                left: aabb_provider_list.pop().unwrap_or(Box::new(EmptyHittable)),
-                right: aabb_provider_list.pop().unwrap_or(Box::new(EmptyHittable)),
-            };
+            .fold(Aabb::default(), |acc, (aabb, _)| acc.union(*aabb));
+        self.aabbs.push(aabb);
+        self.nodes.push(LinearNode::Leaf { first: 0, count: 0 });
+        let centroids = items.iter().fold(Aabb::default(), |acc, (aabb, _)| {
+            let centroid = aabb.centroid();
+            acc.union(Aabb::from_vec3(centroid, centroid))
+        });
+        let axis = centroids.longest_axis();
+        // all the centroids coincide, so no split can separate them
+        if items.len() <= 1 || centroids[axis].length() <= 0.0 {
+            self.leaf(index, items);
+            return index;
         }
-        let axis = aabb.longest_axis();
-        aabb_provider_list.sort_by(|a, b| a.bounding_box()[axis].min.total_cmp(&b.bounding_box()[axis].min));
-        let mid = aabb_provider_list.len() / 2;
-        Self {
-            aabb,
-            left: Box::new(HittableTree::new(aabb_provider_list.split_off(mid))),
-            right: Box::new(HittableTree::new(aabb_provider_list)),
+        let strategy = if depth >= MAX_SAH_DEPTH {
+            SplitStrategy::Median
+        } else {
+            strategy
+        };
+        let (left, right) = match strategy {
+            SplitStrategy::Median if items.len() <= MAX_LEAF_SIZE => {
+                self.leaf(index, items);
+                return index;
+            }
+            SplitStrategy::Median => Self::split_median(items, axis),
+            SplitStrategy::Sah => match Self::split_sah(items, aabb, centroids, axis) {
+                Ok(split) => split,
+                Err(items) => {
+                    self.leaf(index, items);
+                    return index;
+                }
+            },
+        };
+        assert!(depth + 1 < MAX_DEPTH, "BVH is too deep");
+        self.build(left, strategy, depth + 1);
+        let second = self.build(right, strategy, depth + 1);
+        self.nodes[index] = LinearNode::Inner { second, axis };
+        index
+    }
+
+    fn leaf(&mut self, index: usize, items: Items<T>) {
+        self.nodes[index] = LinearNode::Leaf {
+            first: self.items.len(),
+            count: items.len(),
+        };
+        self.items.extend(items.into_iter().map(|(_, item)| item));
+    }
+
+    fn split_median(mut items: Items<T>, axis: usize) -> (Items<T>, Items<T>) {
+        items.sort_by(|(a, _), (b, _)| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+        let right = items.split_off(items.len() / 2);
+        (items, right)
+    }
+
+    // return the items back if a leaf is cheaper than the best split
+    fn split_sah(
+        items: Items<T>,
+        aabb: Aabb,
+        centroids: Aabb,
+        axis: usize,
+    ) -> Result<(Items<T>, Items<T>), Items<T>> {
+        let interval = centroids[axis];
+        let bin = |aabb: &Aabb| {
+            let offset = (aabb.centroid()[axis] - interval.min) / interval.length();
+            ((offset * BIN_COUNT as f64) as usize).min(BIN_COUNT - 1)
+        };
+        let mut bins = [(0, Aabb::default()); BIN_COUNT];
+        for (aabb, _) in &items {
+            let bin = &mut bins[bin(aabb)];
+            bin.0 += 1;
+            bin.1 = bin.1.union(*aabb);
+        }
+        // sweep from both sides to get the count and area of each side of every boundary
+        let mut right_side = [(0, 0.0); BIN_COUNT];
+        let (mut count, mut bounds) = (0, Aabb::default());
+        for i in (1..BIN_COUNT).rev() {
+            count += bins[i].0;
+            bounds = bounds.union(bins[i].1);
+            right_side[i] = (count, bounds.surface_area());
+        }
+        let (mut count, mut bounds) = (0, Aabb::default());
+        let mut best = (f64::INFINITY, 0);
+        for i in 1..BIN_COUNT {
+            count += bins[i - 1].0;
+            bounds = bounds.union(bins[i - 1].1);
+            let (right_count, right_area) = right_side[i];
+            if count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST
+                + (count as f64 * bounds.surface_area() + right_count as f64 * right_area)
+                    / aabb.surface_area();
+            if cost < best.0 {
+                best = (cost, i);
+            }
+        }
+        let leaf_cost = items.len() as f64;
+        if items.len() <= MAX_LEAF_SIZE && leaf_cost <= best.0 {
+            return Err(items);
+        }
+        // degenerate bounds, e.g. a line of points, have no meaningful cost
+        if best.1 == 0 {
+            return Ok(Self::split_median(items, axis));
+        }
+        Ok(items.into_iter().partition(|(aabb, _)| bin(aabb) < best.1))
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        self.aabbs[0]
+    }
+
+    // call hit on every item whose node the ray passes through. return whether any of them hit.
+    // children are visited front to back, and as hits shrink the interval of the hit record,
+    // nodes behind the closest hit so far are culled without testing their items
+    pub fn traverse(
+        &self,
+        hit_record: &mut HitRecord,
+        hit: &mut impl FnMut(&T, &mut HitRecord) -> bool,
+    ) -> bool {
+        self.visit(hit_record, hit, false)
+    }
+
+    // like traverse, but stop at the first item that is hit, which need not be the closest one
+    pub fn traverse_any(
+        &self,
+        hit_record: &mut HitRecord,
+        hit: &mut impl FnMut(&T, &mut HitRecord) -> bool,
+    ) -> bool {
+        self.visit(hit_record, hit, true)
+    }
+
+    fn visit(
+        &self,
+        hit_record: &mut HitRecord,
+        hit: &mut impl FnMut(&T, &mut HitRecord) -> bool,
+        any: bool,
+    ) -> bool {
+        let ray = hit_record.get_ray();
+        let origin = ray.origin;
+        let inverse_direction = Vec3::new(
+            1.0 / ray.direction.x,
+            1.0 / ray.direction.y,
+            1.0 / ray.direction.z,
+        );
+        let negative = [
+            inverse_direction.x < 0.0,
+            inverse_direction.y < 0.0,
+            inverse_direction.z < 0.0,
+        ];
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut index = 0;
+        let mut result = false;
+        loop {
+            if self.aabbs[index].hit_inverse(origin, inverse_direction, hit_record.get_interval()) {
+                match self.nodes[index] {
+                    LinearNode::Leaf { first, count } => {
+                        for item in &self.items[first..first + count] {
+                            result |= hit(item, hit_record);
+                            if any && result {
+                                return true;
+                            }
+                        }
+                    }
+                    LinearNode::Inner { second, axis } => {
+                        // the first child holds the smaller coordinates along the split axis
+                        let (near, far) = if negative[axis] {
+                            (second, index + 1)
+                        } else {
+                            (index + 1, second)
+                        };
+                        stack[stack_size] = far;
+                        stack_size += 1;
+                        index = near;
+                        continue;
+                    }
+                }
+            }
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            index = stack[stack_size];
         }
+        result
     }
 }
 
-impl Hittable for HittableTree {
-    fn hit(&self, hit_record: &mut HitRecord) -> bool {
-        if !self.aabb.hit(hit_record.get_ray(), hit_record.get_interval()) {
-            return false;
+// the items are stored unboxed when they share a type, e.g. the triangles of a mesh
+pub struct HittableTree<T: Hittable = Box<dyn Hittable>> {
+    bvh: Bvh<T>,
+}
+
+impl<T: Hittable> HittableTree<T> {
+    pub fn new(hittable_list: Vec<T>) -> Self {
+        Self::with_strategy(hittable_list, SplitStrategy::default())
+    }
+
+    pub fn with_strategy(hittable_list: Vec<T>, strategy: SplitStrategy) -> Self {
+        let items = hittable_list
+            .into_iter()
+            .map(|hittable| (hittable.bounding_box(), hittable))
+            .collect();
+        Self {
+            bvh: Bvh::new(items, strategy),
         }
-        // note that we don't short-circuit here, because both children need to be hit
-        self.left.hit(hit_record) | self.right.hit(hit_record)
+    }
+}
+
+impl<T: Hittable> Hittable for HittableTree<T> {
+    fn hit(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.bvh.traverse(hit_record, &mut |hittable, hit_record| {
+            hittable.hit(hit_record, sampler)
+        })
+    }
+
+    fn occluded(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.bvh
+            .traverse_any(hit_record, &mut |hittable, hit_record| {
+                hittable.occluded(hit_record, sampler)
+            })
     }
 
     fn bounding_box(&self) -> Aabb {
-        self.aabb
+        self.bvh.bounding_box()
     }
 }
 
@@ -78,17 +329,27 @@ impl HittableList {
     pub fn tree(self) -> HittableTree {
         HittableTree::new(self.hittable_list)
     }
+
+    pub fn tree_with_strategy(self, strategy: SplitStrategy) -> HittableTree {
+        HittableTree::with_strategy(self.hittable_list, strategy)
+    }
 }
 
 impl Hittable for HittableList {
-    fn hit(&self, hit_record: &mut HitRecord) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
         let mut hit_result = false;
         for hittable in &self.hittable_list {
-            hit_result |= hittable.hit(hit_record);
+            hit_result |= hittable.hit(hit_record, sampler);
         }
         hit_result
     }
 
+    fn occluded(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.hittable_list
+            .iter()
+            .any(|hittable| hittable.occluded(hit_record, sampler))
+    }
+
     fn bounding_box(&self) -> Aabb {
         let mut aabb = Aabb::default();
         for shape in &self.hittable_list {
@@ -101,7 +362,7 @@ impl Hittable for HittableList {
 pub struct EmptyShape;
 
 impl Shape for EmptyShape {
-    fn hit(&self, _hit_record: &mut HitRecord, _atlas: &Atlas) -> bool {
+    fn hit(&self, _hit_record: &mut HitRecord, _atlas: &Atlas, _sampler: &mut dyn Sampler) -> bool {
         false
     }
 
@@ -110,48 +371,46 @@ impl Shape for EmptyShape {
     }
 }
 
-pub struct ShapeTree {
-    left: Box<dyn Shape>,
-    right: Box<dyn Shape>,
-    aabb: Aabb,
+pub struct ShapeTree<T: Shape = Box<dyn Shape>> {
+    bvh: Bvh<T>,
 }
 
-impl ShapeTree {
-    pub fn new(mut aabb_provider_list: Vec<Box<dyn Shape>>) -> Self {
-        let aabb = aabb_provider_list
-            .iter()
-            .fold(Aabb::default(), |acc, aabb_provider| {
-                acc.union(aabb_provider.bounding_box())
-            });
-        if aabb_provider_list.len() <= 2 {
-            return ShapeTree {
-                aabb,
-                left: aabb_provider_list.pop().unwrap_or(Box::new(EmptyShape)),
-                right: aabb_provider_list.pop().unwrap_or(Box::new(EmptyShape)),
-            };
-        }
-        let axis = aabb.longest_axis();
-        aabb_provider_list.sort_by(|a, b| a.bounding_box()[axis].min.total_cmp(&b.bounding_box()[axis].min));
-        let mid = aabb_provider_list.len() / 2;
+impl<T: Shape> ShapeTree<T> {
+    pub fn new(shape_list: Vec<T>) -> Self {
+        Self::with_strategy(shape_list, SplitStrategy::default())
+    }
+
+    pub fn with_strategy(shape_list: Vec<T>, strategy: SplitStrategy) -> Self {
+        let items = shape_list
+            .into_iter()
+            .map(|shape| (shape.bounding_box(), shape))
+            .collect();
         Self {
-            aabb,
-            left: Box::new(ShapeTree::new(aabb_provider_list.split_off(mid))),
-            right: Box::new(ShapeTree::new(aabb_provider_list)),
+            bvh: Bvh::new(items, strategy),
         }
     }
 }
 
-impl Shape for ShapeTree {
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool {
-        if !self.aabb.hit(hit_record.get_ray(), hit_record.get_interval()) {
-            return false;
-        }
-        // note that we don't short-circuit here, because both children need to be hit
-        self.left.hit(hit_record, atlas) | self.right.hit(hit_record, atlas)
+impl<T: Shape> Shape for ShapeTree<T> {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
+        self.bvh.traverse(hit_record, &mut |shape, hit_record| {
+            shape.hit(hit_record, atlas, sampler)
+        })
+    }
+
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.bvh.traverse_any(hit_record, &mut |shape, hit_record| {
+            shape.occluded(hit_record, atlas, sampler)
+        })
     }
 
     fn bounding_box(&self) -> Aabb {
-        self.aabb
+        self.bvh.bounding_box()
     }
 }
 
@@ -168,17 +427,32 @@ impl ShapeList {
     pub fn tree(self) -> ShapeTree {
         ShapeTree::new(self.shape_list)
     }
+
+    pub fn tree_with_strategy(self, strategy: SplitStrategy) -> ShapeTree {
+        ShapeTree::with_strategy(self.shape_list, strategy)
+    }
 }
 
 impl Shape for ShapeList {
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
         let mut hit = false;
         for shape in &self.shape_list {
-            hit |= shape.hit(hit_record, atlas);
+            hit |= shape.hit(hit_record, atlas, sampler);
         }
         hit
     }
 
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.shape_list
+            .iter()
+            .any(|shape| shape.occluded(hit_record, atlas, sampler))
+    }
+
     fn bounding_box(&self) -> Aabb {
         let mut aabb = Aabb::default();
         for shape in &self.shape_list {
@@ -186,4 +460,52 @@ impl Shape for ShapeList {
         }
         aabb
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_bvh() {
+    use crate::ray::Ray;
+    use crate::sampler::IndependentSampler;
+    use crate::shape::Sphere;
+
+    let mut sampler = IndependentSampler::new(0);
+    let spheres: Vec<(Vec3, f64)> = (0..200)
+        .map(|_| {
+            (
+                Vec3::random(-10.0, 10.0, &mut sampler),
+                0.1 + sampler.get_1d(),
+            )
+        })
+        .collect();
+    let shapes = || {
+        spheres
+            .iter()
+            .map(|&(center, radius)| Box::new(Sphere::new(center, radius)) as Box<dyn Shape>)
+            .collect::<Vec<_>>()
+    };
+    let list = ShapeList {
+        shape_list: shapes(),
+    };
+    let median = ShapeTree::with_strategy(shapes(), SplitStrategy::Median);
+    let sah = ShapeTree::with_strategy(shapes(), SplitStrategy::Sah);
+    let atlas = Atlas::default();
+    for _ in 0..500 {
+        let ray = Ray::new(
+            Vec3::random(-15.0, 15.0, &mut sampler),
+            Vec3::random_unit_vector(&mut sampler),
+        );
+        let hits = [&list as &dyn Shape, &median, &sah].map(|shape| {
+            let mut hit_record = HitRecord::new(ray.clone());
+            shape
+                .hit(&mut hit_record, &atlas, &mut sampler)
+                .then(|| hit_record.get_hit().t)
+        });
+        assert_eq!(hits[0], hits[1]);
+        assert_eq!(hits[0], hits[2]);
+        for shape in [&list as &dyn Shape, &median, &sah] {
+            let mut hit_record = HitRecord::new(ray.clone());
+            let occluded = shape.occluded(&mut hit_record, &atlas, &mut sampler);
+            assert_eq!(occluded, hits[0].is_some());
+        }
+    }
+}
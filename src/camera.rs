@@ -1,9 +1,9 @@
-use rand::Rng;
-
 use crate::onb::Onb;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::Vec3;
 
+#[derive(Clone, Copy, Debug)]
 pub struct PerspectiveParam {
     pub look_from: Vec3,
     // blender (x, y, z) -> (x, z, -y).
@@ -12,12 +12,14 @@ pub struct PerspectiveParam {
     pub view_up: Vec3,
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct LensParam {
     pub fov: f64, // fov of width in degrees
     pub defocus_angle: f64,
     pub focus_dist: f64, // distance from the camera to the focal plane. should be positive.
 }
 
+#[derive(Clone, Copy, Debug)]
 pub struct ImageParam {
     pub image_width: u32,
     pub image_height: u32,
@@ -31,7 +33,7 @@ pub struct Camera {
     viewport_v: Vec3,
     pixel_width_ratio: f64,
     pixel_height_ratio: f64,
-    sqrt_spp: u32,
+    samples_per_pixel: u32,
     defocus_disk_u: Vec3,
     defocus_disk_v: Vec3,
 }
@@ -67,8 +69,6 @@ impl Camera {
             lens_param.focus_dist * (lens_param.defocus_angle.to_radians() / 2.0).tan();
         let defocus_disk_u = uvw.local(Vec3::new(defocus_radius, 0.0, 0.0));
         let defocus_disk_v = uvw.local(Vec3::new(0.0, defocus_radius, 0.0));
-        let sample_per_pixel = canvas_param.sample_per_pixel;
-        let sqrt_spp = (sample_per_pixel as f64).sqrt() as u32;
         Self {
             origin: perspective_param.look_from,
             viewport_upper_left,
@@ -76,34 +76,33 @@ impl Camera {
             viewport_v,
             pixel_width_ratio,
             pixel_height_ratio,
-            sqrt_spp,
+            samples_per_pixel: canvas_param.sample_per_pixel.max(1),
             defocus_disk_u,
             defocus_disk_v,
         }
     }
 
-    fn get_ray(&self, u: f64, v: f64) -> Ray {
-        let origin = self.defocus_disk_sample();
+    fn get_ray(&self, u: f64, v: f64, sampler: &mut dyn Sampler) -> Ray {
+        let origin = self.defocus_disk_sample(sampler);
         let direction =
             self.viewport_upper_left + self.viewport_u * u + self.viewport_v * v - origin;
-        Ray::new(origin, direction)
+        Ray::with_time(origin, direction, sampler.get_1d())
     }
 
-    fn defocus_disk_sample(&self) -> Vec3 {
-        let p = Vec3::random_in_unit_disk();
+    fn defocus_disk_sample(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let p = Vec3::random_in_unit_disk(sampler);
         self.origin + (self.defocus_disk_u * p.x) + (self.defocus_disk_v * p.y)
     }
 
-    pub fn get_ray_at(&self, i: u32, j: u32, si: u32, sj: u32) -> Ray {
-        let mut rng = rand::thread_rng();
-        let u = (i as f64 + (si as f64 + rng.gen::<f64>()) / self.sqrt_spp as f64 - 0.5)
-            * self.pixel_width_ratio;
-        let v = (j as f64 + (sj as f64 + rng.gen::<f64>()) / self.sqrt_spp as f64 - 0.5)
-            * self.pixel_height_ratio;
-        self.get_ray(u, v)
+    // the sampler stratifies the position in the pixel along with the other dimensions
+    pub fn get_ray_at(&self, i: u32, j: u32, sampler: &mut dyn Sampler) -> Ray {
+        let (du, dv) = sampler.get_2d();
+        let u = (i as f64 + du - 0.5) * self.pixel_width_ratio;
+        let v = (j as f64 + dv - 0.5) * self.pixel_height_ratio;
+        self.get_ray(u, v, sampler)
     }
 
-    pub fn sqrt_spp(&self) -> u32 {
-        self.sqrt_spp
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
     }
 }
@@ -1,10 +1,14 @@
 use console::style;
-use image::{ImageBuffer, RgbImage};
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageBuffer, Rgb, RgbImage};
 use std::fs;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use crate::color::Color;
 use crate::texture::UV;
+use crate::tone_map::ToneMap;
 
 pub struct Canvas {
     image: RgbImage,
@@ -17,10 +21,12 @@ impl Canvas {
         }
     }
     pub fn from_path(path: &str) -> Self {
-        let image = image::open(path)
-            .expect("Cannot open the image file")
-            .to_rgb8();
-        Self { image }
+        Self::open(path).expect("Cannot open the image file")
+    }
+
+    pub fn open(path: &str) -> image::ImageResult<Self> {
+        let image = image::open(path)?.to_rgb8();
+        Ok(Self { image })
     }
 
     pub fn empty(width: u32, height: u32) -> Self {
@@ -42,8 +48,11 @@ impl Canvas {
     }
 
     pub fn write(&mut self, x: u32, y: u32, color: Color) {
-        let pixel = self.image.get_pixel_mut(x, y);
-        *pixel = color.into();
+        self.write_rgb(x, y, color.into());
+    }
+
+    pub fn write_rgb(&mut self, x: u32, y: u32, rgb: Rgb<u8>) {
+        *self.image.get_pixel_mut(x, y) = rgb;
     }
 
     pub fn width(&self) -> u32 {
@@ -73,18 +82,399 @@ impl Canvas {
         path
     }
 
-    pub fn save(&self, path: &str) {
-        let path = std::path::Path::new(path);
-        let prefix = path.parent().unwrap();
-        fs::create_dir_all(prefix).expect("Cannot create all the parents");
+    // create the parent directories and pick a path that doesn't overwrite anything
+    fn prepare_output(path: &str) -> PathBuf {
+        let path = Self::create_parents(path);
         let path_buf = Self::rename_if_exists(path.to_path_buf());
-        let path = path_buf.as_path();
         println!(
             "Output image as \"{}\"",
-            style(path.to_str().unwrap()).green()
+            style(path_buf.to_str().unwrap()).green()
         );
+        path_buf
+    }
+
+    fn create_parents(path: &str) -> &Path {
+        let path = Path::new(path);
+        let prefix = path.parent().unwrap();
+        fs::create_dir_all(prefix).expect("Cannot create all the parents");
+        path
+    }
+
+    pub fn save(&self, path: &str) {
+        let path_buf = Self::prepare_output(path);
         self.image
-            .save(path)
+            .save(path_buf.as_path())
+            .expect("Cannot save the image to the file");
+    }
+
+    // unlike save(), replace the file if it exists. used for snapshots
+    pub fn overwrite(&self, path: &str) {
+        self.image
+            .save(Self::create_parents(path))
+            .expect("Cannot save the image to the file");
+    }
+}
+
+// high dynamic range accumulation buffer. stores the radiance sum and sample count of each pixel,
+// and the sum of squared luminance for estimating the variance
+#[derive(Clone)]
+pub struct HdrCanvas {
+    width: u32,
+    height: u32,
+    sum: Vec<Color>,
+    sum_sq: Vec<f64>,
+    samples: Vec<u32>,
+}
+
+impl HdrCanvas {
+    pub const EXTENSIONS: [&'static str; 3] = ["exr", "pfm", "hdr"];
+
+    pub fn empty(width: u32, height: u32) -> Self {
+        let size = (width * height) as usize;
+        Self {
+            width,
+            height,
+            sum: vec![Color::BLACK; size],
+            sum_sq: vec![0.0; size],
+            samples: vec![0; size],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    // add the sum of several samples and the sum of their squared luminance to a pixel
+    pub fn add(&mut self, x: u32, y: u32, sum: Color, sum_sq: f64, samples: u32) {
+        let index = self.index(x, y);
+        self.sum[index] += sum;
+        self.sum_sq[index] += sum_sq;
+        self.samples[index] += samples;
+    }
+
+    pub fn add_sample(&mut self, x: u32, y: u32, color: Color) {
+        self.add(x, y, color, color.luminance().powi(2), 1);
+    }
+
+    pub fn samples(&self, x: u32, y: u32) -> u32 {
+        self.samples[self.index(x, y)]
+    }
+
+    // the radiance sum of a pixel
+    pub fn sum(&self, x: u32, y: u32) -> Color {
+        self.sum[self.index(x, y)]
+    }
+
+    pub fn sum_sq(&self, x: u32, y: u32) -> f64 {
+        self.sum_sq[self.index(x, y)]
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.samples.iter().map(|&n| n as u64).sum()
+    }
+
+    // standard error of the mean luminance relative to the mean. infinite with fewer than 2 samples.
+    // dark pixels are measured against a small floor so that they don't sample forever
+    pub fn relative_error(&self, x: u32, y: u32) -> f64 {
+        const MIN_LUMINANCE: f64 = 1e-2;
+        let index = self.index(x, y);
+        let n = self.samples[index] as f64;
+        if n < 2.0 {
+            return f64::INFINITY;
+        }
+        let mean = self.sum[index].luminance() / n;
+        let variance = ((self.sum_sq[index] - mean * mean * n) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() / mean.abs().max(MIN_LUMINANCE)
+    }
+
+    // add all the samples of another film of the same size
+    pub fn merge(&mut self, other: &HdrCanvas) {
+        assert!(
+            self.width == other.width && self.height == other.height,
+            "Cannot merge films of different sizes"
+        );
+        for (sum, other) in self.sum.iter_mut().zip(&other.sum) {
+            *sum += *other;
+        }
+        for (sum_sq, other) in self.sum_sq.iter_mut().zip(&other.sum_sq) {
+            *sum_sq += *other;
+        }
+        for (samples, other) in self.samples.iter_mut().zip(&other.samples) {
+            *samples += *other;
+        }
+    }
+
+    // the average radiance of a pixel
+    pub fn read(&self, x: u32, y: u32) -> Color {
+        let index = self.index(x, y);
+        match self.samples[index] {
+            0 => Color::BLACK,
+            n => self.sum[index] / n as f64,
+        }
+    }
+
+    pub fn to_canvas(&self, tone_map: &ToneMap) -> Canvas {
+        let mut canvas = Canvas::empty(self.width, self.height);
+        for x in 0..self.width {
+            for y in 0..self.height {
+                canvas.write_rgb(x, y, tone_map.to_rgb(self.read(x, y)));
+            }
+        }
+        canvas
+    }
+
+    pub fn is_hdr_path(path: &str) -> bool {
+        Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| Self::EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+    }
+
+    // save as OpenEXR, PFM or Radiance HDR according to the extension
+    pub fn save(&self, path: &str) {
+        let write = Self::writer(path);
+        self.write_file(&Canvas::prepare_output(path), write);
+    }
+
+    // unlike save(), replace the file if it exists. used for snapshots
+    pub fn overwrite(&self, path: &str) {
+        let write = Self::writer(path);
+        self.write_file(Canvas::create_parents(path), write);
+    }
+
+    fn writer(path: &str) -> fn(&Self, &mut BufWriter<File>) -> std::io::Result<()> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("exr") => Self::write_exr,
+            Some("pfm") => Self::write_pfm,
+            Some("hdr") => Self::write_hdr,
+            _ => panic!("Unsupported HDR image format: \"{}\"", path),
+        }
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        write: fn(&Self, &mut BufWriter<File>) -> std::io::Result<()>,
+    ) {
+        let mut writer = BufWriter::new(File::create(path).expect("Cannot create the image file"));
+        write(self, &mut writer)
+            .and_then(|_| writer.flush())
             .expect("Cannot save the image to the file");
     }
+
+    fn pixel_f32(&self, x: u32, y: u32) -> [f32; 3] {
+        let color = self.read(x, y);
+        [color.r as f32, color.g as f32, color.b as f32]
+    }
+
+    // portable float map. scanlines go from bottom to top
+    fn write_pfm(&self, writer: &mut BufWriter<File>) -> std::io::Result<()> {
+        // negative scale means little endian
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                for c in self.pixel_f32(x, y) {
+                    writer.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_hdr(&self, writer: &mut BufWriter<File>) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity((self.width * self.height) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                data.push(Rgb(self.pixel_f32(x, y)));
+            }
+        }
+        HdrEncoder::new(writer)
+            .encode(&data, self.width as usize, self.height as usize)
+            .map_err(std::io::Error::other)
+    }
+
+    // uncompressed scanline OpenEXR with 32-bit float B, G, R channels
+    fn write_exr(&self, writer: &mut BufWriter<File>) -> std::io::Result<()> {
+        fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+            header.extend_from_slice(name.as_bytes());
+            header.push(0);
+            header.extend_from_slice(kind.as_bytes());
+            header.push(0);
+            header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            header.extend_from_slice(value);
+        }
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]); // magic number
+        header.extend_from_slice(&2i32.to_le_bytes()); // version 2, single part scanline
+
+        // channels must be sorted by name
+        let mut channels = Vec::new();
+        for name in ["B", "G", "R"] {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&2i32.to_le_bytes()); // float
+            channels.extend_from_slice(&[0, 0, 0, 0]); // p_linear and reserved
+            channels.extend_from_slice(&1i32.to_le_bytes()); // x sampling
+            channels.extend_from_slice(&1i32.to_le_bytes()); // y sampling
+        }
+        channels.push(0);
+        attribute(&mut header, "channels", "chlist", &channels);
+        attribute(&mut header, "compression", "compression", &[0]);
+        let mut window = Vec::new();
+        for v in [0, 0, self.width as i32 - 1, self.height as i32 - 1] {
+            window.extend_from_slice(&v.to_le_bytes());
+        }
+        attribute(&mut header, "dataWindow", "box2i", &window);
+        attribute(&mut header, "displayWindow", "box2i", &window);
+        attribute(&mut header, "lineOrder", "lineOrder", &[0]); // increasing y
+        attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        header.push(0);
+        writer.write_all(&header)?;
+        // offset table, one uncompressed scanline per block
+        let line_size = self.width as u64 * 3 * 4;
+        let table_end = header.len() as u64 + self.height as u64 * 8;
+        for y in 0..self.height as u64 {
+            writer.write_all(&(table_end + y * (8 + line_size)).to_le_bytes())?;
+        }
+        for y in 0..self.height {
+            writer.write_all(&(y as i32).to_le_bytes())?;
+            writer.write_all(&(line_size as i32).to_le_bytes())?;
+            for c in [2, 1, 0] {
+                for x in 0..self.width {
+                    writer.write_all(&self.pixel_f32(x, y)[c].to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_film() -> HdrCanvas {
+    let mut film = HdrCanvas::empty(3, 2);
+    for y in 0..2 {
+        for x in 0..3 {
+            let color = Color::new(x as f64, y as f64, 0.5 + (x + 3 * y) as f64);
+            film.add_sample(x, y, color);
+        }
+    }
+    film
+}
+
+#[test]
+fn test_pfm() {
+    let film = test_film();
+    let path = std::env::temp_dir().join("raytracer_test.pfm");
+    let path = path.to_str().unwrap();
+    film.overwrite(path);
+    let bytes = fs::read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    let header = b"PF\n3 2\n-1.0\n";
+    assert_eq!(&bytes[..header.len()], header);
+    let values: Vec<f32> = bytes[header.len()..]
+        .chunks(4)
+        .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    assert_eq!(values.len(), 3 * 2 * 3);
+    // the bottom row comes first
+    for (i, pixel) in values.chunks(3).enumerate() {
+        let (x, y) = (i as u32 % 3, 1 - i as u32 / 3);
+        assert_eq!(pixel, film.pixel_f32(x, y));
+    }
+}
+
+#[test]
+fn test_exr() {
+    let film = test_film();
+    let path = std::env::temp_dir().join("raytracer_test.exr");
+    let path = path.to_str().unwrap();
+    film.overwrite(path);
+    let bytes = fs::read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    let i32_at = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    let string_at = |i: usize| {
+        let end = i + bytes[i..].iter().position(|&b| b == 0).unwrap();
+        (String::from_utf8(bytes[i..end].to_vec()).unwrap(), end + 1)
+    };
+    assert_eq!(bytes[..4], [0x76, 0x2f, 0x31, 0x01]);
+    assert_eq!(i32_at(4), 2);
+    // the attributes up to the empty name that ends the header
+    let mut attributes = Vec::new();
+    let mut i = 8;
+    loop {
+        let (name, next) = string_at(i);
+        if name.is_empty() {
+            i = next;
+            break;
+        }
+        let (kind, next) = string_at(next);
+        let size = i32_at(next) as usize;
+        attributes.push((name, kind, next + 4));
+        i = next + 4 + size;
+    }
+    for name in [
+        "channels",
+        "compression",
+        "dataWindow",
+        "displayWindow",
+        "lineOrder",
+        "pixelAspectRatio",
+        "screenWindowCenter",
+        "screenWindowWidth",
+    ] {
+        assert!(attributes.iter().any(|a| a.0 == name), "{}", name);
+    }
+    let (_, kind, start) = attributes.iter().find(|a| a.0 == "channels").unwrap();
+    assert_eq!(kind, "chlist");
+    let mut names = Vec::new();
+    let mut j = *start;
+    while bytes[j] != 0 {
+        let (name, next) = string_at(j);
+        names.push(name);
+        j = next + 16;
+    }
+    assert_eq!(names, ["B", "G", "R"]);
+    // one offset per scanline, each pointing at its y, its size and the B, G and R values
+    let table_end = i + 2 * 8;
+    let offsets: Vec<usize> = bytes[i..table_end]
+        .chunks(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as usize)
+        .collect();
+    assert_eq!(offsets[0], table_end);
+    for (y, &offset) in offsets.iter().enumerate() {
+        assert_eq!(i32_at(offset), y as i32);
+        assert_eq!(i32_at(offset + 4), 3 * 3 * 4);
+        for (c, channel) in [2, 1, 0].into_iter().enumerate() {
+            for x in 0..3 {
+                let at = offset + 8 + (c * 3 + x) * 4;
+                let value = f32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+                assert_eq!(value, film.pixel_f32(x as u32, y as u32)[channel]);
+            }
+        }
+    }
+    assert_eq!(bytes.len(), offsets[1] + 8 + 3 * 3 * 4);
 }
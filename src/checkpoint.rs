@@ -0,0 +1,239 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+use crate::canvas::HdrCanvas;
+use crate::color::Color;
+use crate::sampler::SamplerKind;
+
+// the state of an interrupted render: the accumulated film, the scene, the seed, the sampling
+// and where sampling stopped
+pub struct Checkpoint {
+    pub scene: u64, // a hash of the scene file, see Scene::id
+    pub seed: u64,
+    pub next_sample: u32, // sample indices [0, next_sample) are in the film
+    pub sampler: SamplerKind,
+    pub samples_per_pixel: u32, // which the sample indices are drawn from, e.g. for stratification
+    pub film: HdrCanvas,
+}
+
+impl Checkpoint {
+    const MAGIC: &'static [u8; 4] = b"RTCK";
+    const VERSION: u32 = 1;
+    // how sampler kinds are stored, by index. only append to it
+    const SAMPLERS: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    // write to a temporary file first so that an interruption never leaves a broken checkpoint
+    pub fn save(&self, path: &str) -> Result<()> {
+        let path = Path::new(path);
+        if let Some(prefix) = path.parent() {
+            fs::create_dir_all(prefix)?;
+        }
+        let temp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        writer.write_all(Self::MAGIC)?;
+        writer.write_all(&Self::VERSION.to_le_bytes())?;
+        writer.write_all(&self.film.width().to_le_bytes())?;
+        writer.write_all(&self.film.height().to_le_bytes())?;
+        writer.write_all(&self.scene.to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&self.next_sample.to_le_bytes())?;
+        let sampler = Self::SAMPLERS
+            .iter()
+            .position(|&kind| kind == self.sampler)
+            .unwrap() as u32;
+        writer.write_all(&sampler.to_le_bytes())?;
+        writer.write_all(&self.samples_per_pixel.to_le_bytes())?;
+        for y in 0..self.film.height() {
+            for x in 0..self.film.width() {
+                let sum = self.film.sum(x, y);
+                for c in [sum.r, sum.g, sum.b] {
+                    writer.write_all(&c.to_le_bytes())?;
+                }
+                writer.write_all(&self.film.sum_sq(x, y).to_le_bytes())?;
+                writer.write_all(&self.film.samples(x, y).to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(temp, path)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a checkpoint file"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != Self::VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported checkpoint version {}", version),
+            ));
+        }
+        let width = read_u32(&mut reader)?;
+        let height = read_u32(&mut reader)?;
+        let scene = read_u64(&mut reader)?;
+        let seed = read_u64(&mut reader)?;
+        let next_sample = read_u32(&mut reader)?;
+        let sampler = read_u32(&mut reader)?;
+        let sampler = *Self::SAMPLERS.get(sampler as usize).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown sampler {}", sampler),
+            )
+        })?;
+        let samples_per_pixel = read_u32(&mut reader)?;
+        let mut film = HdrCanvas::empty(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let r = read_f64(&mut reader)?;
+                let g = read_f64(&mut reader)?;
+                let b = read_f64(&mut reader)?;
+                let sum_sq = read_f64(&mut reader)?;
+                let samples = read_u32(&mut reader)?;
+                film.add(x, y, Color::new(r, g, b), sum_sq, samples);
+            }
+        }
+        Ok(Self {
+            scene,
+            seed,
+            next_sample,
+            sampler,
+            samples_per_pixel,
+            film,
+        })
+    }
+
+    // whether a render of the given scene, size and sampling can continue from the checkpoint.
+    // the samples drawn after a resume only line up with the saved ones if all of them match
+    pub fn check_resume(
+        &self,
+        scene: u64,
+        width: u32,
+        height: u32,
+        sampler: SamplerKind,
+        samples_per_pixel: u32,
+    ) -> std::result::Result<(), String> {
+        if self.scene != scene {
+            return Err("the checkpoint is of another scene".to_string());
+        }
+        if self.film.width() != width || self.film.height() != height {
+            return Err(format!(
+                "the checkpoint is {}x{} but the image is {}x{}",
+                self.film.width(),
+                self.film.height(),
+                width,
+                height
+            ));
+        }
+        if self.sampler != sampler {
+            return Err(format!(
+                "the checkpoint uses the {:?} sampler but the render uses {:?}",
+                self.sampler, sampler
+            ));
+        }
+        if self.samples_per_pixel != samples_per_pixel {
+            return Err(format!(
+                "the checkpoint has {} spp but the render has {}",
+                self.samples_per_pixel, samples_per_pixel
+            ));
+        }
+        Ok(())
+    }
+
+    // combine renders of the same scene, e.g. from different machines. they should use different seeds,
+    // otherwise the same samples are counted twice
+    pub fn merge(&mut self, other: &Checkpoint) -> std::result::Result<(), String> {
+        if self.scene != other.scene {
+            return Err("cannot merge checkpoints of different scenes".to_string());
+        }
+        if self.film.width() != other.film.width() || self.film.height() != other.film.height() {
+            return Err(format!(
+                "cannot merge a {}x{} checkpoint into a {}x{} one",
+                other.film.width(),
+                other.film.height(),
+                self.film.width(),
+                self.film.height()
+            ));
+        }
+        if self.sampler != other.sampler || self.samples_per_pixel != other.samples_per_pixel {
+            return Err(format!(
+                "cannot merge a checkpoint of the {:?} sampler at {} spp into one of the {:?} sampler at {} spp",
+                other.sampler, other.samples_per_pixel, self.sampler, self.samples_per_pixel
+            ));
+        }
+        self.film.merge(&other.film);
+        self.next_sample = self.next_sample.max(other.next_sample);
+        Ok(())
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(reader: &mut impl Read) -> Result<f64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[test]
+fn test_checkpoint() {
+    let mut film = HdrCanvas::empty(3, 2);
+    film.add(1, 1, Color::new(0.5, 1.5, 2.5), 3.0, 4);
+    film.add_sample(2, 0, Color::new(1.0, 2.0, 3.0));
+    let checkpoint = Checkpoint {
+        scene: 11,
+        seed: 7,
+        next_sample: 4,
+        sampler: SamplerKind::Stratified,
+        samples_per_pixel: 16,
+        film,
+    };
+    let path = std::env::temp_dir().join("raytracer_test_checkpoint.ckpt");
+    let path = path.to_str().unwrap();
+    checkpoint.save(path).unwrap();
+    let mut loaded = Checkpoint::load(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(loaded.scene, 11);
+    assert_eq!(loaded.seed, 7);
+    assert_eq!(loaded.next_sample, 4);
+    assert_eq!(loaded.sampler, SamplerKind::Stratified);
+    assert_eq!(loaded.samples_per_pixel, 16);
+    assert_eq!(loaded.film.samples(1, 1), 4);
+    assert_eq!(loaded.film.read(1, 1).g, 1.5 / 4.0);
+    assert_eq!(loaded.film.sum_sq(1, 1), 3.0);
+    loaded.merge(&checkpoint).unwrap();
+    assert_eq!(loaded.film.samples(2, 0), 2);
+    assert_eq!(loaded.film.read(2, 0).b, 3.0);
+    // the scene and the sampling must match to resume or merge, like the size
+    let (stratified, sobol) = (SamplerKind::Stratified, SamplerKind::Sobol);
+    assert!(loaded.check_resume(11, 3, 2, stratified, 16).is_ok());
+    assert!(loaded.check_resume(12, 3, 2, stratified, 16).is_err());
+    assert!(loaded.check_resume(11, 3, 3, stratified, 16).is_err());
+    assert!(loaded.check_resume(11, 3, 2, sobol, 16).is_err());
+    assert!(loaded.check_resume(11, 3, 2, stratified, 64).is_err());
+    loaded.samples_per_pixel = 64;
+    assert!(loaded.merge(&checkpoint).is_err());
+    loaded.samples_per_pixel = 16;
+    loaded.scene = 12;
+    assert!(loaded.merge(&checkpoint).is_err());
+}
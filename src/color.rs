@@ -1,6 +1,6 @@
 use crate::interval::Interval;
+use crate::sampler::Sampler;
 use image::Rgb;
-use rand::Rng;
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign};
 
 #[derive(Clone, Copy, Debug)]
@@ -53,13 +53,18 @@ impl Color {
         gamma_component * gamma_component
     }
 
-    pub fn random(min: f64, max: f64) -> Color {
-        let mut rng = rand::thread_rng();
-        Self::new(
-            rng.gen_range(min..max),
-            rng.gen_range(min..max),
-            rng.gen_range(min..max),
-        )
+    pub fn random(min: f64, max: f64, sampler: &mut dyn Sampler) -> Color {
+        let mut value = || min + (max - min) * sampler.get_1d();
+        Self::new(value(), value(), value())
+    }
+
+    // Rec. 709 relative luminance
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    pub fn max_component(&self) -> f64 {
+        self.r.max(self.g).max(self.b)
     }
 
     // abandon nan and inf
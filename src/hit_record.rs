@@ -1,46 +1,16 @@
+use crate::bsdf::BSDF;
 use crate::color::Color;
-use crate::hit_record::Scatter::{Absorb, ScatterPDF, ScatterRay};
+use crate::hit_record::Scatter::{Absorb, ScatterBSDF};
 use crate::interval::Interval;
 use crate::onb::Onb;
-use crate::pdf::{ShapePDF, PDF};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::texture::{Atlas, UV};
 use crate::vec3::Vec3;
 
 pub enum Scatter {
     Absorb,
-    ScatterPDF(Box<dyn PDF>),
-    ScatterRay(Ray),
-}
-
-impl Scatter {
-    pub fn pdf(&self) -> &dyn PDF {
-        match *self {
-            ScatterPDF(ref pdf) => pdf.as_ref(),
-            _ => panic!("Scatter::pdf() called on non-PDF scatter"),
-        }
-    }
-
-    pub fn move_pdf(self) -> Box<dyn PDF> {
-        match self {
-            ScatterPDF(pdf) => pdf,
-            _ => panic!("Scatter::pdf() called on non-PDF scatter"),
-        }
-    }
-
-    pub fn ray(&self) -> &Ray {
-        match *self {
-            ScatterRay(ref ray) => ray,
-            _ => panic!("Scatter::ray() called on non-Ray scatter"),
-        }
-    }
-
-    pub fn move_ray(self) -> Ray {
-        match self {
-            ScatterRay(ray) => ray,
-            _ => panic!("Scatter::ray() called on non-Ray scatter"),
-        }
-    }
+    ScatterBSDF(Box<dyn BSDF>),
 }
 
 pub struct HitInfo {
@@ -50,7 +20,6 @@ pub struct HitInfo {
     pub front_face: bool, // whether outside the object
     pub uv: UV,
     pub emission: Color,
-    pub attenuation: Color,
     pub scatter: Scatter,
 }
 
@@ -85,7 +54,6 @@ impl HitRecord {
             front_face,
             uv,
             emission: Color::BLACK,
-            attenuation: Color::WHITE,
             scatter: Absorb,
         }
     }
@@ -115,13 +83,8 @@ impl HitRecord {
         }
     }
 
-    pub fn set_scatter_ray(&mut self, direction: Vec3) {
-        self.get_hit_mut().scatter =
-            ScatterRay(self.ray.new_ray(self.get_hit().position, direction))
-    }
-
-    pub fn set_scatter_pdf<T: PDF + 'static>(&mut self, pdf: T) {
-        self.get_hit_mut().scatter = ScatterPDF(Box::new(pdf))
+    pub fn set_scatter_bsdf<T: BSDF + 'static>(&mut self, bsdf: T) {
+        self.get_hit_mut().scatter = ScatterBSDF(Box::new(bsdf))
     }
 
     pub fn set_scatter_absorb(&mut self) {
@@ -145,27 +108,86 @@ impl HitRecord {
         self.hit_info.unwrap()
     }
 
-    // generate a new ray from the shape pdf mixed with the scatter pdf
-    // return (new_ray, prob_of_mixture_pdf, prob_of_scatter_pdf)
-    // if shape pdf is empty, use scatter pdf only
-    pub fn generate_scatter(&self, light_pdf: &ShapePDF) -> (Ray, f64, f64) {
-        let scatter_pdf = self.get_hit().scatter.pdf();
-        let origin = self.get_hit().position;
-        let v = if light_pdf.empty() || rand::random::<f64>() < 0.5 {
-            scatter_pdf.generate()
-        } else {
-            light_pdf.generate(origin)
-        };
-        let value = if light_pdf.empty() {
-            scatter_pdf.prob(v)
-        } else {
-            0.5 * scatter_pdf.prob(v) + 0.5 * light_pdf.prob(v, origin)
-        };
-        (
-            self.ray.new_ray(self.get_hit().position, v),
-            value,
-            scatter_pdf.prob(v),
-        )
+    // the bsdf times the cosine towards direction, and the probability of scattering there
+    pub fn eval_scatter(&self, direction: Vec3) -> (Color, f64) {
+        match &self.get_hit().scatter {
+            ScatterBSDF(bsdf) => {
+                let outgoing = -self.ray.direction.normalize();
+                (
+                    bsdf.eval(outgoing, direction),
+                    bsdf.prob(outgoing, direction),
+                )
+            }
+            Absorb => (Color::BLACK, 0.0),
+        }
+    }
+
+    // the probability of scattering back along the ray, had the light arrived from direction.
+    // used when paths are sampled in the opposite direction
+    pub fn reverse_scatter_prob(&self, direction: Vec3) -> f64 {
+        self.scatter_prob(direction, -self.ray.direction.normalize())
+    }
+
+    // the probability of scattering towards incoming, had the light left towards outgoing
+    pub fn scatter_prob(&self, outgoing: Vec3, incoming: Vec3) -> f64 {
+        match &self.get_hit().scatter {
+            ScatterBSDF(bsdf) => bsdf.prob(outgoing, incoming),
+            Absorb => 0.0,
+        }
+    }
+
+    // the next ray of the path, what its throughput is multiplied by, and the probability of
+    // choosing it, which is None for delta lobes. None if the path is absorbed
+    pub fn sample_scatter(&self, sampler: &mut dyn Sampler) -> Option<(Ray, Color, Option<f64>)> {
+        let hit = self.get_hit();
+        match &hit.scatter {
+            Absorb => None,
+            ScatterBSDF(bsdf) => {
+                let sample = bsdf.sample(-self.ray.direction.normalize(), sampler)?;
+                let ray = self.ray.new_ray(hit.position, sample.direction);
+                Some((ray, sample.weight, sample.prob))
+            }
+        }
+    }
+
+    // eval_scatter for light arriving along the ray and leaving towards direction, which paths
+    // traced from the lights use
+    pub fn eval_scatter_adjoint(&self, direction: Vec3) -> (Color, f64) {
+        match &self.get_hit().scatter {
+            ScatterBSDF(bsdf) => {
+                let outgoing = -self.ray.direction.normalize();
+                (
+                    bsdf.eval_adjoint(outgoing, direction),
+                    bsdf.prob(outgoing, direction),
+                )
+            }
+            Absorb => (Color::BLACK, 0.0),
+        }
+    }
+
+    // sample_scatter for paths traced from the lights
+    pub fn sample_scatter_adjoint(
+        &self,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, Color, Option<f64>)> {
+        let (ray, weight, prob) = self.sample_scatter(sampler)?;
+        match (&self.get_hit().scatter, prob) {
+            (ScatterBSDF(bsdf), Some(prob)) => {
+                let outgoing = -self.ray.direction.normalize();
+                let value = bsdf.eval_adjoint(outgoing, ray.direction.normalize());
+                Some((ray, value / prob, Some(prob)))
+            }
+            _ => Some((ray, weight, prob)),
+        }
+    }
+
+    // whether the hit only scatters into delta lobes, so that eval_scatter is always zero and
+    // nothing can be connected to it. also true when it absorbs
+    pub fn is_delta(&self) -> bool {
+        match &self.get_hit().scatter {
+            Absorb => true,
+            ScatterBSDF(bsdf) => bsdf.is_delta(),
+        }
     }
 
     pub fn get_ray(&self) -> &Ray {
@@ -1,18 +1,40 @@
 use crate::aabb::Aabb;
-use crate::bvh::{HittableTree, HittableList};
+use crate::bvh::{HittableTree, HittableList, SplitStrategy};
 use crate::color::Color;
 use crate::hit_record::HitRecord;
+use crate::interval::Interval;
 use crate::material::Material;
 use crate::pdf::ShapePDF;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::shape::{Shape, ShapePDFProvider};
 use crate::texture::Atlas;
+use crate::vec3::Vec3;
 
 pub trait Hittable: Sync + Send {
     // similar to shape but needn't be supplied with atlas
-    fn hit(&self, hit_record: &mut HitRecord) -> bool;
+    fn hit(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool;
+    // like Shape::occluded. the material isn't evaluated
+    fn occluded(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.hit(hit_record, sampler)
+    }
     fn bounding_box(&self) -> Aabb;
 }
 
+impl<T: Hittable + ?Sized> Hittable for Box<T> {
+    fn hit(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.as_ref().hit(hit_record, sampler)
+    }
+
+    fn occluded(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.as_ref().occluded(hit_record, sampler)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.as_ref().bounding_box()
+    }
+}
+
 pub struct Object<S: Shape, M: Material> {
     pub shape: S,
     pub material: M,
@@ -20,12 +42,17 @@ pub struct Object<S: Shape, M: Material> {
 }
 
 impl<S: Shape, M: Material> Hittable for Object<S, M> {
-    fn hit(&self, hit_record: &mut HitRecord) -> bool {
-        self.shape.hit(hit_record, &self.atlas) && {
-            self.material.scatter(hit_record, &self.atlas);
+    fn hit(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.shape.hit(hit_record, &self.atlas, sampler) && {
+            self.material.scatter(hit_record, &self.atlas, sampler);
             true
         }
     }
+
+    fn occluded(&self, hit_record: &mut HitRecord, sampler: &mut dyn Sampler) -> bool {
+        self.shape.occluded(hit_record, &self.atlas, sampler)
+    }
+
     fn bounding_box(&self) -> Aabb {
         self.shape.bounding_box()
     }
@@ -37,11 +64,63 @@ pub struct World {
     pub background: Color,
 }
 
+impl World {
+    // the sky seen by rays that escape the scene, darker towards the bottom
+    pub fn background(&self, ray: &Ray) -> Color {
+        let a = 0.5 * (ray.direction.normalize().y + 1.0);
+        self.background * a
+    }
+
+    // whether anything blocks the segment from the origin of ray to target, e.g. a shadow ray
+    // towards a point on a light. the ray only supplies the origin and the time
+    pub fn occluded(&self, ray: &Ray, target: Vec3, sampler: &mut dyn Sampler) -> bool {
+        let offset = target - ray.origin;
+        let distance = offset.length();
+        let mut hit_record = HitRecord::new(ray.new_ray(ray.origin, offset / distance));
+        hit_record.set_interval(Interval::new(Interval::DELTA, distance - Interval::DELTA));
+        self.objects.occluded(&mut hit_record, sampler)
+    }
+
+    // pick a point on the lights uniformly by area, for paths that start there. return the point,
+    // the normal on the side that emits, the emitted radiance and the density by area. None if
+    // there are no lights or the point doesn't emit
+    pub fn sample_emitter(
+        &self,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Vec3, Vec3, Color, f64)> {
+        if self.light_pdf.empty() {
+            return None;
+        }
+        let (position, normal, prob) = self.light_pdf.sample_area(sampler);
+        // the light is only a shape. its radiance comes from the object it stands for, seen from
+        // just outside. one-sided emitters may face against the normal of the shape
+        [normal, -normal].into_iter().find_map(|normal| {
+            let probe = Ray::with_time(position + normal, -normal, time);
+            let emission = self.emission(&probe, 1.0, sampler);
+            (emission.max_component() > 0.0).then_some((position, normal, emission, prob))
+        })
+    }
+
+    // the radiance emitted back along ray by whatever is at t, e.g. a point sampled on the
+    // lights. only that point is looked at, so nothing in front of it is evaluated
+    pub fn emission(&self, ray: &Ray, t: f64, sampler: &mut dyn Sampler) -> Color {
+        let mut probe = HitRecord::new(ray.clone());
+        probe.set_interval(Interval::new(t - Interval::DELTA, t + Interval::DELTA));
+        if self.objects.hit(&mut probe, sampler) {
+            probe.get_hit().emission
+        } else {
+            Color::BLACK
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct WorldBuilder {
     objects: HittableList,
     light_pdf: ShapePDF,
     background: Option<Color>,
+    strategy: SplitStrategy,
 }
 
 impl WorldBuilder {
@@ -70,11 +149,89 @@ impl WorldBuilder {
         self.background = Some(color);
     }
 
+    // how the tree over the objects is built
+    pub fn set_split_strategy(&mut self, strategy: SplitStrategy) {
+        self.strategy = strategy;
+    }
+
     pub fn build(self) -> World {
         World {
-            objects: self.objects.tree(),
+            objects: self.objects.tree_with_strategy(self.strategy),
             light_pdf: self.light_pdf,
             background: self.background.unwrap_or(Color::BLACK),
         }
     }
 }
+
+// a white floor at y = 0 lit by a 2x2 light facing down from y = 2, both centred on the y axis.
+// the light is sampled if lights is set. tests may add more objects
+#[cfg(test)]
+pub fn lit_box(lights: bool) -> WorldBuilder {
+    use crate::material::{Emissive, Lambertian};
+    use crate::shape::Quad;
+    use crate::texture::SolidColor;
+
+    let light = || {
+        Quad::new(
+            Vec3::new(-1.0, 2.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+        )
+    };
+    let floor = Quad::new(
+        Vec3::new(-2.0, 0.0, -2.0),
+        Vec3::new(0.0, 0.0, 4.0),
+        Vec3::new(4.0, 0.0, 0.0),
+    );
+    let mut builder = WorldBuilder::default();
+    let emission = Atlas::default().set_emission(SolidColor::new(Color::WHITE));
+    builder.add_object(light(), Emissive::new(4.0), emission);
+    builder.add_object(floor, Lambertian, Atlas::default());
+    if lights {
+        builder.add_light(light());
+    }
+    builder
+}
+
+#[test]
+fn test_occluded() {
+    use crate::material::Lambertian;
+    use crate::mesh::Triangle;
+    use crate::sampler::IndependentSampler;
+    use crate::texture::{SolidColor, UV};
+
+    let world = |blocker: Atlas| {
+        let mut builder = lit_box(true);
+        // only triangles have cut-outs
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let triangle = Triangle::vertex(
+            Vec3::new(-0.4, 1.0, -0.4),
+            Vec3::new(0.5, 1.0, -0.4),
+            Vec3::new(-0.4, 1.0, 0.5),
+            UV::default(),
+            UV::default(),
+            UV::default(),
+            up,
+            up,
+            up,
+        );
+        builder.add_object(triangle, Lambertian, blocker);
+        builder.build()
+    };
+    let mut sampler = IndependentSampler::new(0);
+    let origin = Ray::new(Vec3::default(), Vec3::new(0.0, 1.0, 0.0));
+    let target = Vec3::new(0.0, 2.0, 0.0);
+    // the blocker is in the way, but not of a segment beside it. neither the floor the segment
+    // starts on nor the light at the target block it
+    let opaque = world(Atlas::default());
+    assert!(opaque.occluded(&origin, target, &mut sampler));
+    assert!(!opaque.occluded(&origin, Vec3::new(0.9, 2.0, 0.0), &mut sampler));
+    let beside = Ray::new(Vec3::new(0.9, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+    assert!(!opaque.occluded(&beside, Vec3::new(0.9, 2.0, 0.0), &mut sampler));
+    // cut-outs let the shadow ray through
+    let cut_out = world(Atlas::default().set_transparency(SolidColor::new(Color::BLACK)));
+    assert!(!cut_out.occluded(&origin, target, &mut sampler));
+    // only the point itself is looked at for its emission, which faces down
+    assert_eq!(opaque.emission(&beside, 2.0, &mut sampler).g, 4.0);
+    assert_eq!(opaque.emission(&beside, 1.0, &mut sampler).g, 0.0);
+}
@@ -0,0 +1,233 @@
+use std::str::FromStr;
+
+use crate::bdpt::BidirectionalPathTracer;
+use crate::color::Color;
+use crate::hit_record::HitRecord;
+use crate::hittable::{Hittable, World};
+use crate::pdf::power_heuristic;
+use crate::photon::PhotonMapper;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+
+// estimates the light arriving at the camera. RayTracer takes care of pixels and samples
+pub trait Integrator: Sync + Send {
+    // the radiance arriving along a camera ray
+    fn radiance(&self, world: &World, ray: Ray, sampler: &mut dyn Sampler) -> Color;
+    // work done once before rendering, e.g. building a photon map
+    fn preprocess(&mut self, _world: &World, _seed: u64) {}
+}
+
+impl<T: Integrator + ?Sized> Integrator for Box<T> {
+    fn radiance(&self, world: &World, ray: Ray, sampler: &mut dyn Sampler) -> Color {
+        self.as_ref().radiance(world, ray, sampler)
+    }
+
+    fn preprocess(&mut self, world: &World, seed: u64) {
+        self.as_mut().preprocess(world, seed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IntegratorKind {
+    #[default]
+    Path,
+    Bidirectional,
+    Photon,
+}
+
+// settings of the integrators. None keeps the default, and integrators ignore what they don't use
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IntegratorOptions {
+    pub roulette_depth: Option<u32>,
+    pub photons: Option<u32>,
+    pub photon_radius: Option<f64>,
+}
+
+impl IntegratorKind {
+    pub fn create(self, max_depth: u32, options: &IntegratorOptions) -> Box<dyn Integrator> {
+        let roulette_depth = options
+            .roulette_depth
+            .unwrap_or(PathTracer::DEFAULT_ROULETTE_DEPTH);
+        match self {
+            IntegratorKind::Path => {
+                Box::new(PathTracer::new(max_depth).set_roulette_depth(roulette_depth))
+            }
+            IntegratorKind::Bidirectional => {
+                Box::new(BidirectionalPathTracer::new(max_depth).set_roulette_depth(roulette_depth))
+            }
+            IntegratorKind::Photon => {
+                let mut integrator = PhotonMapper::new(max_depth)
+                    .set_roulette_depth(roulette_depth)
+                    .set_photons(options.photons.unwrap_or(PhotonMapper::DEFAULT_PHOTONS));
+                if let Some(radius) = options.photon_radius {
+                    integrator = integrator.set_radius(radius);
+                }
+                Box::new(integrator)
+            }
+        }
+    }
+}
+
+impl FromStr for IntegratorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "path" => Ok(IntegratorKind::Path),
+            "bdpt" | "bidirectional" => Ok(IntegratorKind::Bidirectional),
+            "photon" => Ok(IntegratorKind::Photon),
+            _ => Err(format!("unknown integrator \"{}\"", s)),
+        }
+    }
+}
+
+// unidirectional path tracing with next-event estimation, multiple importance sampling and
+// russian roulette
+pub struct PathTracer {
+    max_depth: u32,
+    // bounces before russian roulette may end a path
+    roulette_depth: u32,
+}
+
+impl PathTracer {
+    pub const DEFAULT_ROULETTE_DEPTH: u32 = 5;
+
+    pub fn new(max_depth: u32) -> Self {
+        Self {
+            max_depth,
+            roulette_depth: Self::DEFAULT_ROULETTE_DEPTH,
+        }
+    }
+
+    // after this many bounces, paths are continued with a probability that follows their
+    // throughput. a depth of at least max_depth turns russian roulette off
+    pub fn set_roulette_depth(mut self, depth: u32) -> Self {
+        self.roulette_depth = depth;
+        self
+    }
+
+    // next-event estimation: the light from a sampled point on the lights if a shadow ray reaches
+    // it, weighted against scattering in the same direction. the shadow ray stops at any hit, and
+    // only the light is evaluated for its emission
+    pub fn sample_light(world: &World, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Color {
+        let light_pdf = &world.light_pdf;
+        if light_pdf.empty() {
+            return Color::BLACK;
+        }
+        let position = hit_record.get_hit().position;
+        let direction = light_pdf.generate(position, sampler).normalize();
+        let light_prob = light_pdf.prob(direction, position);
+        let (value, scatter_prob) = hit_record.eval_scatter(direction);
+        if light_prob <= 0.0 || scatter_prob <= 0.0 {
+            return Color::BLACK;
+        }
+        let shadow = hit_record.get_ray().new_ray(position, direction);
+        let Some(distance) = light_pdf.hit_distance(&shadow, sampler) else {
+            return Color::BLACK;
+        };
+        if world.occluded(&shadow, shadow.at(distance), sampler) {
+            return Color::BLACK;
+        }
+        let radiance = world.emission(&shadow, distance, sampler);
+        radiance * value * (power_heuristic(light_prob, scatter_prob) / light_prob)
+    }
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, world: &World, ray: Ray, sampler: &mut dyn Sampler) -> Color {
+        let mut radiance = Color::BLACK;
+        let mut throughput = Color::WHITE;
+        let mut ray = ray;
+        // the probability of the last vertex choosing ray by scattering, if it also sampled the
+        // lights. emission is then weighted against next-event estimation reaching the same light
+        let mut scatter_prob = None;
+        for depth in 0..self.max_depth {
+            let mut hit_record = HitRecord::new(ray);
+            let hit = world.objects.hit(&mut hit_record, sampler);
+            let weight = match scatter_prob {
+                Some(scatter_prob) if !world.light_pdf.empty() => {
+                    let ray = hit_record.get_ray();
+                    power_heuristic(
+                        scatter_prob,
+                        world.light_pdf.prob(ray.direction, ray.origin),
+                    )
+                }
+                _ => 1.0,
+            };
+            if !hit {
+                radiance += throughput * world.background(hit_record.get_ray()) * weight;
+                break;
+            }
+            radiance += throughput * hit_record.get_hit().emission * weight;
+            // light arriving after the last bounce isn't counted, nor is its estimate
+            if !hit_record.is_delta() && depth + 1 < self.max_depth {
+                radiance += throughput * Self::sample_light(world, &hit_record, sampler);
+            }
+            let Some((next, attenuation, prob)) = hit_record.sample_scatter(sampler) else {
+                break;
+            };
+            ray = next;
+            scatter_prob = prob;
+            throughput = throughput * attenuation;
+            // paths that carry little light are likely to end. the survivors are divided by the
+            // probability of surviving to stay unbiased
+            if depth >= self.roulette_depth {
+                let probability = throughput.max_component().min(0.95);
+                if sampler.get_1d() >= probability {
+                    break;
+                }
+                throughput /= probability;
+            }
+        }
+        radiance
+    }
+}
+
+#[test]
+fn test_next_event() {
+    use crate::hittable::lit_box;
+    use crate::material::Lambertian;
+    use crate::sampler::IndependentSampler;
+    use crate::shape::Quad;
+    use crate::texture::Atlas;
+    use crate::vec3::Vec3;
+
+    // a ray onto the floor under the light
+    let ray = || Ray::new(Vec3::new(0.0, 0.5, 1.0), Vec3::new(0.0, -0.5, -1.0));
+    let mean = |world: &World, samples: u32| {
+        let integrator = PathTracer::new(4);
+        let mut sampler = IndependentSampler::new(0);
+        let mut sum = Color::BLACK;
+        for _ in 0..samples {
+            sum += integrator.radiance(world, ray(), &mut sampler);
+        }
+        sum.g / samples as f64
+    };
+    // sampling the light and scattering estimate the same light, the former with less noise
+    let sampled = mean(&lit_box(true).build(), 20000);
+    let scattered = mean(&lit_box(false).build(), 200000);
+    assert!(sampled > 0.1);
+    assert!((sampled / scattered - 1.0).abs() < 0.03);
+
+    // a blocker between the floor and the light cuts off the light it would sample
+    let mut builder = lit_box(true);
+    let blocker = Quad::new(
+        Vec3::new(-1.5, 1.0, -1.5),
+        Vec3::new(3.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 3.0),
+    );
+    builder.add_object(blocker, Lambertian, Atlas::default());
+    let blocked = builder.build();
+    let lit = lit_box(true).build();
+    let mut sampler = IndependentSampler::new(0);
+    let (mut blocked_sum, mut lit_sum) = (Color::BLACK, Color::BLACK);
+    for _ in 0..1000 {
+        for (world, sum) in [(&blocked, &mut blocked_sum), (&lit, &mut lit_sum)] {
+            let mut hit_record = HitRecord::new(ray());
+            assert!(world.objects.hit(&mut hit_record, &mut sampler));
+            *sum += PathTracer::sample_light(world, &hit_record, &mut sampler);
+        }
+    }
+    assert_eq!(blocked_sum.max_component(), 0.0);
+    assert!(lit_sum.max_component() > 0.0);
+}
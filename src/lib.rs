@@ -1,19 +1,28 @@
 pub mod aabb;
+pub mod bdpt;
+pub mod bsdf;
 pub mod bvh;
 pub mod camera;
 pub mod canvas;
+pub mod checkpoint;
 pub mod color;
 pub mod hit_record;
 pub mod hittable;
+pub mod integrator;
 pub mod interval;
 pub mod material;
 pub mod mesh;
+pub mod microfacet;
 pub mod noise;
 pub mod onb;
 pub mod pdf;
+pub mod photon;
 pub mod ray;
 pub mod raytracer;
+pub mod sampler;
+pub mod scene;
 pub mod shape;
 pub mod texture;
+pub mod tone_map;
 pub mod transform;
 pub mod vec3;
@@ -1,233 +1,314 @@
-use raytracer::bvh::ShapeList;
-use raytracer::camera::{Camera, ImageParam, LensParam, PerspectiveParam};
-use raytracer::canvas::Canvas;
-use raytracer::color::Color;
-use raytracer::hittable::WorldBuilder;
-use raytracer::material::{Dielectric, Emissive, Isotropic, Lambertian, Metal, Translucent};
-use raytracer::mesh::Mesh;
-use raytracer::raytracer::RayTracer;
-use raytracer::shape::{ConstantMedium, Sphere};
-use raytracer::texture::{Atlas, ImageTexture, SolidColor};
-use raytracer::vec3::Vec3;
+use std::process;
+use std::str::FromStr;
+
+use raytracer::canvas::{Canvas, HdrCanvas};
+use raytracer::checkpoint::Checkpoint;
+use raytracer::integrator::{IntegratorKind, IntegratorOptions};
+use raytracer::raytracer::{AdaptiveSampling, RayTracer, SnapshotInterval};
+use raytracer::sampler::SamplerKind;
+use raytracer::scene::Scene;
+use raytracer::tone_map::{Operator, ToneMap, Transfer};
+
+const USAGE: &str = "\
+Usage: raytracer [SCENE] [OPTIONS]
+
+Arguments:
+  [SCENE]                  scene file to render [default: assets/cornell_box.json]
+
+Options:
+  -o, --output <PATH>      output image. .exr, .pfm and .hdr keep the full dynamic range
+                           [default: output/final/final_final_scene.png]
+  -w, --width <PIXELS>     image width. keeps the aspect ratio if --height is omitted
+  -H, --height <PIXELS>    image height. keeps the aspect ratio if --width is omitted
+  -s, --spp <SAMPLES>      samples per pixel
+      --adaptive <THRESHOLD>
+                           stop sampling pixels whose relative noise is below THRESHOLD, e.g. 0.01,
+                           and spend the saved samples on noisy pixels. --spp is the average budget
+      --min-spp <SAMPLES>  samples before a pixel may converge [default: 16]
+      --max-spp <SAMPLES>  samples a noisy pixel may take [default: 4 * spp]
+  -d, --max-depth <DEPTH>  maximum number of bounces
+      --integrator <NAME>  path, bdpt (bidirectional) or photon (caustics from a photon map)
+                           [default: path]
+      --photons <COUNT>    photons shot by the photon integrator [default: 200000]
+      --photon-radius <R>  radius photons are gathered in [default: 0.002 * scene diagonal]
+      --roulette-depth <DEPTH>
+                           bounces before russian roulette may end dim paths [default: 5]
+  -t, --threads <COUNT>    worker threads [default: available parallelism]
+      --seed <SEED>        random seed [default: 0]
+      --sampler <NAME>     independent, stratified, halton or sobol [default: sobol]
+  -e, --exposure <EV>      exposure compensation in stops [default: 0]
+      --tone-map <OP>      clamp, reinhard, aces or hable [default: clamp]
+      --transfer <FN>      gamma (square root) or srgb [default: gamma]
+      --snapshot <PATH>    render progressively and keep overwriting PATH with the current image
+      --snapshot-passes <N>
+                           write the snapshot every N passes (one sample per pixel each)
+      --snapshot-seconds <S>
+                           write the snapshot every S seconds [default: 30]
+      --checkpoint <PATH>  save the render state to PATH periodically and when finished
+      --checkpoint-passes <N>
+                           save the checkpoint every N passes
+      --checkpoint-seconds <S>
+                           save the checkpoint every S seconds [default: 300]
+      --resume <PATH>      continue the render saved in a checkpoint. the scene, size, sampler
+                           and spp must match
+      --merge <PATH>       don't render. add up the samples of the given checkpoints, which should
+                           have different seeds, and write the output image. can be repeated
+  -h, --help               print this message";
+
+#[derive(Default)]
+struct Args {
+    scene: Option<String>,
+    output: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    spp: Option<u32>,
+    adaptive: Option<f64>,
+    min_spp: Option<u32>,
+    max_spp: Option<u32>,
+    max_depth: Option<u32>,
+    integrator: IntegratorKind,
+    integrator_options: IntegratorOptions,
+    threads: Option<u32>,
+    seed: Option<u64>,
+    sampler: Option<SamplerKind>,
+    tone_map: ToneMap,
+    snapshot: Option<String>,
+    snapshot_interval: Option<SnapshotInterval>,
+    checkpoint: Option<String>,
+    checkpoint_interval: Option<SnapshotInterval>,
+    resume: Option<String>,
+    merge: Vec<String>,
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> T {
+    let value = value.unwrap_or_else(|| fail(&format!("{} requires a value", flag)));
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(&format!("invalid value \"{}\" for {}", value, flag)))
+}
+
+impl Args {
+    fn parse() -> Self {
+        let mut args = Args::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                "-o" | "--output" => args.output = Some(value(&arg, iter.next())),
+                "-w" | "--width" => args.width = Some(value(&arg, iter.next())),
+                "-H" | "--height" => args.height = Some(value(&arg, iter.next())),
+                "-s" | "--spp" => args.spp = Some(value(&arg, iter.next())),
+                "--adaptive" => args.adaptive = Some(value(&arg, iter.next())),
+                "--min-spp" => args.min_spp = Some(value(&arg, iter.next())),
+                "--max-spp" => args.max_spp = Some(value(&arg, iter.next())),
+                "-d" | "--max-depth" => args.max_depth = Some(value(&arg, iter.next())),
+                "--roulette-depth" => {
+                    args.integrator_options.roulette_depth = Some(value(&arg, iter.next()))
+                }
+                "--integrator" => args.integrator = value(&arg, iter.next()),
+                "--photons" => args.integrator_options.photons = Some(value(&arg, iter.next())),
+                "--photon-radius" => {
+                    args.integrator_options.photon_radius = Some(value(&arg, iter.next()))
+                }
+                "-t" | "--threads" => args.threads = Some(value(&arg, iter.next())),
+                "--seed" => args.seed = Some(value(&arg, iter.next())),
+                "--sampler" => args.sampler = Some(value(&arg, iter.next())),
+                "-e" | "--exposure" => args.tone_map.exposure = value(&arg, iter.next()),
+                "--tone-map" => args.tone_map.operator = value::<Operator>(&arg, iter.next()),
+                "--transfer" => args.tone_map.transfer = value::<Transfer>(&arg, iter.next()),
+                "--snapshot" => args.snapshot = Some(value(&arg, iter.next())),
+                "--snapshot-passes" => {
+                    args.snapshot_interval =
+                        Some(SnapshotInterval::Passes(value(&arg, iter.next())))
+                }
+                "--snapshot-seconds" => {
+                    args.snapshot_interval =
+                        Some(SnapshotInterval::Seconds(value(&arg, iter.next())))
+                }
+                "--checkpoint" => args.checkpoint = Some(value(&arg, iter.next())),
+                "--checkpoint-passes" => {
+                    args.checkpoint_interval =
+                        Some(SnapshotInterval::Passes(value(&arg, iter.next())))
+                }
+                "--checkpoint-seconds" => {
+                    args.checkpoint_interval =
+                        Some(SnapshotInterval::Seconds(value(&arg, iter.next())))
+                }
+                "--resume" => args.resume = Some(value(&arg, iter.next())),
+                "--merge" => args.merge.push(value(&arg, iter.next())),
+                _ if arg.starts_with('-') => fail(&format!("unknown option \"{}\"", arg)),
+                _ if args.scene.is_none() => args.scene = Some(arg),
+                _ => fail(&format!("unexpected argument \"{}\"", arg)),
+            }
+        }
+        if args.snapshot_interval.is_some() && args.snapshot.is_none() {
+            fail("--snapshot-passes and --snapshot-seconds require --snapshot");
+        }
+        if (args.min_spp.is_some() || args.max_spp.is_some()) && args.adaptive.is_none() {
+            fail("--min-spp and --max-spp require --adaptive");
+        }
+        if args.checkpoint_interval.is_some() && args.checkpoint.is_none() {
+            fail("--checkpoint-passes and --checkpoint-seconds require --checkpoint");
+        }
+        if !args.merge.is_empty() && (args.scene.is_some() || args.resume.is_some()) {
+            fail("--merge doesn't take a scene or --resume");
+        }
+        args
+    }
+}
+
+fn load_checkpoint(path: &str) -> Checkpoint {
+    Checkpoint::load(path).unwrap_or_else(|e| {
+        eprintln!("Cannot load the checkpoint \"{}\": {}", path, e);
+        process::exit(1);
+    })
+}
+
+fn merge(args: &Args) {
+    let mut merged = load_checkpoint(&args.merge[0]);
+    let mut seeds = vec![merged.seed];
+    for path in &args.merge[1..] {
+        let checkpoint = load_checkpoint(path);
+        if seeds.contains(&checkpoint.seed) {
+            eprintln!(
+                "warning: \"{}\" has the same seed {} as another checkpoint and adds no new information",
+                path, checkpoint.seed
+            );
+        }
+        seeds.push(checkpoint.seed);
+        merged.merge(&checkpoint).unwrap_or_else(|e| {
+            eprintln!("Cannot merge \"{}\": {}", path, e);
+            process::exit(1);
+        });
+    }
+    println!(
+        "Merged {} checkpoints at {}x{}",
+        args.merge.len(),
+        merged.film.width(),
+        merged.film.height()
+    );
+    let output = args
+        .output
+        .as_deref()
+        .unwrap_or("output/final/final_final_scene.png");
+    if HdrCanvas::is_hdr_path(output) {
+        merged.film.save(output);
+    } else {
+        merged.film.to_canvas(&args.tone_map).save(output);
+    }
+}
 
 fn main() {
-    let mut world = WorldBuilder::default();
-    let mut mesh = Mesh::load_obj("assets/test2.obj");
-    println!("Mesh: {:?}", mesh.get_names());
-    world.set_background(Color::new_u8(0x7F, 0xFF, 0xD4) * 0.5);
-    world.add_object(
-        mesh.remove_shape("deco1"),
-        Metal::new(0.1),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new_u8(0xC0, 0xC0, 0xC0))),
-    );
-    world.add_object(
-        mesh.remove_shape("deco2"),
-        Metal::new(0.1),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new_u8(236, 197, 192))),
-    );
-    world.add_object(
-        mesh.remove_shape("key1_chain"),
-        Dielectric::new(1.5),
-        Atlas::default(),
-    );
-    world.add_object(
-        mesh.remove_shape("key1_up"),
-        Translucent::new(1.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key1_alpha.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key1_down"),
-        Translucent::new(1.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key1_alpha.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key1_mid"),
-        Lambertian,
-        Atlas::default()
-            .set_transparency(ImageTexture::new("assets/key1_icon_alpha.png"))
-            .set_attenuation(SolidColor::new(Color::BLACK)),
-    );
-    world.add_object(
-        mesh.remove_shape("key1_side"),
-        Dielectric::new(1.5),
-        Atlas::default(),
-    );
-    world.add_object(
-        mesh.remove_shape("key2_chain"),
-        Lambertian,
-        Atlas::default().set_attenuation(SolidColor::new(Color::WHITE)),
-    );
-    world.add_object(
-        mesh.remove_shape("key2_up"),
-        Dielectric::new(1.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key2_alpha.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key2_down"),
-        Dielectric::new(1.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key2_alpha.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key2_mid"),
-        Lambertian,
-        Atlas::default()
-            .set_transparency(ImageTexture::new("assets/key2_icon_alpha.png"))
-            .set_attenuation(ImageTexture::new("assets/key2_icon.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key2_side"),
-        Dielectric::new(1.5),
-        Atlas::default(),
-    );
-    world.add_object(
-        mesh.remove_shape("key3_chain"),
-        Lambertian,
-        Atlas::default()
-            .set_attenuation(SolidColor::new(Color::BLACK)),
-    );
-    world.add_object(
-        mesh.remove_shape("key3_up"),
-        Dielectric::new(1.5),
-        Atlas::default()
-            .set_transparency(ImageTexture::new("assets/key3_alpha.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key3_down"),
-        Dielectric::new(1.5),
-        Atlas::default()
-            .set_transparency(ImageTexture::new("assets/key3_alpha.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key3_front"),
-        Lambertian,
-        Atlas::default()
-            .set_transparency(ImageTexture::new("assets/key3_icon_alpha.png"))
-            .set_attenuation(ImageTexture::new("assets/key3_icon.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("key3_side"),
-        Dielectric::new(1.5),
-        Atlas::default(),
-    );
-    let mut boundary = ShapeList::default();
-    boundary.push(mesh.remove_shape("key3_up_boundary"));
-    boundary.push(mesh.remove_shape("key3_down_boundary"));
-    boundary.push(mesh.remove_shape("key3_side_boundary"));
-    world.add_object(
-        ConstantMedium::new(5.0, boundary),
-        Isotropic::new(0.4),
-        Atlas::default()
-            .set_transparency(ImageTexture::new("assets/key3_alpha.png"))
-            .set_attenuation(SolidColor::new(Color::new_u8(51, 102, 153)))
-            .set_emission(SolidColor::new(Color::new_u8(51, 102, 153))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_chain"),
-        Metal::new(0.2),
-        Atlas::default(),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_in_up"),
-        Metal::new(0.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key4_in_alpha.png"))
-            .set_normal(ImageTexture::new("assets/key4_in_normal.png"))
-            .set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_in_down"),
-        Metal::new(0.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key4_in_alpha.png"))
-            .set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_in_side"),
-        Metal::new(0.5),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_out_up"),
-        Metal::new(0.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key4_out_alpha.png"))
-            .set_normal(ImageTexture::new("assets/key4_out_normal.png"))
-            .set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_out_down"),
-        Metal::new(0.5),
-        Atlas::default().set_transparency(ImageTexture::new("assets/key4_out_alpha.png"))
-            .set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_out_side"),
-        Metal::new(0.5),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_ring"),
-        Metal::new(0.5),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key4_cylinder"),
-        Metal::new(0.5),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new(0.804, 0.498, 0.196))),
-    );
-    world.add_object(
-        mesh.remove_shape("key5_chain"),
-        Metal::new(0.2),
-        Atlas::default(),
-    );
-    world.add_object(
-        mesh.remove_shape("key5"),
-        Metal::new(0.2),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new(1.0, 0.84, 0.0))),
-    );
-    world.add_object(
-        mesh.remove_shape("box_back"),
-        Emissive::new(1.0),
-        Atlas::default().set_emission(ImageTexture::new("assets/emission.png")),
-    );
-    world.add_object(
-        mesh.remove_shape("box_down"),
-        Metal::new(0.5),
-        Atlas::default().set_attenuation(ImageTexture::new("assets/wood.png")),
-    );
-    world.add_object(
-        Sphere::new(Vec3::new(6.5, 13.0, 0.0), 1.0),
-        Emissive::new(20.0),
-        Atlas::default().set_emission(SolidColor::new(Color::new_u8(253, 94, 83))),
-    );
-    world.add_object(
-        ConstantMedium::new(0.03, Sphere::new(Vec3::new(0.0, 4.0, 0.0), 3.75)),
-        Isotropic::new(0.2),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new_u8(255, 0, 255))),
-    );
-    world.add_object(
-        ConstantMedium::new(0.02, Sphere::new(Vec3::new(0.0, 4.0, 0.0), 7.5)),
-        Isotropic::new(0.2),
-        Atlas::default().set_attenuation(SolidColor::new(Color::new_u8(255, 0, 255))),
-    );
+    let args = Args::parse();
+    if !args.merge.is_empty() {
+        merge(&args);
+        return;
+    }
+    let scene_path = args.scene.as_deref().unwrap_or("assets/cornell_box.json");
+    let mut scene = Scene::load(scene_path).unwrap_or_else(|e| {
+        eprintln!("Cannot load the scene: {}", e);
+        process::exit(1);
+    });
+    for warning in &scene.warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    let image = &mut scene.image;
+    match (args.width, args.height) {
+        (Some(width), Some(height)) => {
+            image.image_width = width;
+            image.image_height = height;
+        }
+        (Some(width), None) => {
+            image.image_height =
+                (width as u64 * image.image_height as u64 / image.image_width as u64) as u32;
+            image.image_width = width;
+        }
+        (None, Some(height)) => {
+            image.image_width =
+                (height as u64 * image.image_width as u64 / image.image_height as u64) as u32;
+            image.image_height = height;
+        }
+        (None, None) => {}
+    }
+    if image.image_width == 0 || image.image_height == 0 {
+        fail("the image size must be positive");
+    }
+    if let Some(spp) = args.spp {
+        image.sample_per_pixel = spp;
+    }
+    if let Some(max_depth) = args.max_depth {
+        scene.max_depth = max_depth;
+    }
 
-    let image_width = 4000;
-    let image_height = 2000;
-    let camera = Camera::new(
-        PerspectiveParam {
-            look_from: Vec3::new(45.0, 12.0, 0.0),
-            look_at: Vec3::new(0.0, 2.95, 0.0),
-            view_up: Vec3::new(0.0, 1.0, 0.0), // y-axis is up
-        },
-        LensParam {
-            fov: 28.0,
-            defocus_angle: 1.5,
-            focus_dist: 40.0,
-        },
-        ImageParam {
-            image_width,
-            image_height,
-            sample_per_pixel: 1000,
-        },
+    let camera = scene.camera();
+    let picture = Canvas::empty(scene.image.image_width, scene.image.image_height);
+    let integrator = args
+        .integrator
+        .create(scene.max_depth, &args.integrator_options);
+    let mut raytracer = RayTracer::new(camera, picture, scene.world.build(), integrator)
+        .set_scene(scene.id)
+        .set_tone_map(args.tone_map);
+    if let Some(threads) = args.threads {
+        raytracer = raytracer.set_threads(threads);
+    }
+    if let Some(seed) = args.seed {
+        raytracer = raytracer.set_seed(seed);
+    }
+    if let Some(sampler) = args.sampler {
+        raytracer = raytracer.set_sampler(sampler);
+    }
+    if let Some(snapshot) = &args.snapshot {
+        let interval = args
+            .snapshot_interval
+            .unwrap_or(SnapshotInterval::Seconds(30.0));
+        raytracer = raytracer.set_progressive(snapshot, interval);
+    }
+    if let Some(threshold) = args.adaptive {
+        let spp = scene.image.sample_per_pixel;
+        let max_samples = args.max_spp.unwrap_or(spp.saturating_mul(4));
+        let min_samples = args.min_spp.unwrap_or(16).min(max_samples);
+        raytracer = raytracer.set_adaptive(AdaptiveSampling {
+            threshold,
+            min_samples,
+            max_samples,
+        });
+    }
+    if let Some(checkpoint) = &args.checkpoint {
+        let interval = args
+            .checkpoint_interval
+            .unwrap_or(SnapshotInterval::Seconds(300.0));
+        raytracer = raytracer.set_checkpoint(checkpoint, interval);
+    }
+    if let Some(path) = &args.resume {
+        let checkpoint = load_checkpoint(path);
+        let next_sample = checkpoint.next_sample;
+        raytracer = raytracer.resume(checkpoint).unwrap_or_else(|e| {
+            eprintln!("Cannot resume: {}", e);
+            process::exit(1);
+        });
+        println!("Resuming \"{}\" from sample {}", path, next_sample);
+    }
+    println!(
+        "Rendering \"{}\" at {}x{}, {} spp, max depth {}, seed {}",
+        scene_path,
+        scene.image.image_width,
+        scene.image.image_height,
+        scene.image.sample_per_pixel,
+        scene.max_depth,
+        raytracer.seed()
+    );
+    raytracer.render().save(
+        args.output
+            .as_deref()
+            .unwrap_or("output/final/final_final_scene.png"),
     );
-    let picture = Canvas::empty(image_width, image_height);
-    let raytracer = RayTracer::new(camera, picture, world.build(), 50);
-    raytracer.render().save("output/final/final_final_scene.png");
 }
@@ -1,22 +1,82 @@
+use crate::bsdf::{
+    ConductorBSDF, DielectricBSDF, IsotropicBSDF, LambertianBSDF, MetalBSDF, RoughDielectricBSDF,
+    TintedBSDF, TranslucentBSDF, BSDF,
+};
 use crate::color::Color;
-use crate::hit_record::HitRecord;
-use crate::pdf::{CosineHemisphere, UniformSphere};
+use crate::hit_record::{HitRecord, Scatter};
+use crate::microfacet::GGX;
+use crate::sampler::Sampler;
 use crate::texture::Atlas;
-use crate::vec3::Vec3;
 
 pub trait Material: Sync + Send {
     // hit_record.ray and hit_record.hit are the original ray and hit info
-    // should set hit_record.scatter to three possible values (Absorb by default)
-    // may decorate emission and attenuation
-    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas);
+    // should set hit_record.scatter to one of the possible values (Absorb by default)
+    // may decorate emission. scatters with the bsdf by default
+    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas, _sampler: &mut dyn Sampler) {
+        if let Some(bsdf) = self.bsdf(hit_record, atlas) {
+            hit_record.get_hit_mut().scatter = Scatter::ScatterBSDF(bsdf);
+        }
+    }
+    // how the material scatters light at the hit, for evaluating, sampling and finding the
+    // probability of any pair of directions. None if it doesn't scatter light
+    fn bsdf(&self, _hit_record: &HitRecord, _atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        None
+    }
+}
+
+impl<T: Material + ?Sized> Material for Box<T> {
+    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) {
+        self.as_ref().scatter(hit_record, atlas, sampler)
+    }
+
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        self.as_ref().bsdf(hit_record, atlas)
+    }
+}
+
+// the index of refraction of the side of the hit the ray comes from over that of the other side
+fn refraction_ratio(hit_record: &HitRecord, refraction_index: f64) -> f64 {
+    if hit_record.get_hit().front_face {
+        1.0 / refraction_index
+    } else {
+        refraction_index
+    }
+}
+
+// beer-lambert absorption. a ray that hits from inside crossed the medium since its previous hit,
+// and the bsdf is tinted by the share of light left. absorption is per unit of distance. the
+// absorption texture in the atlas scales it, or gives it alone. the texture is only read where the
+// ray leaves, as if the whole way had that absorption, and rays ending at other objects inside
+// aren't absorbed
+fn absorb(
+    bsdf: Box<dyn BSDF>,
+    hit_record: &HitRecord,
+    atlas: &Atlas,
+    absorption: Option<Color>,
+) -> Box<dyn BSDF> {
+    let hit = hit_record.get_hit();
+    if hit.front_face || (absorption.is_none() && atlas.absorption.is_none()) {
+        return bsdf;
+    }
+    let absorption = absorption.unwrap_or(Color::WHITE) * atlas.get_absorption(hit);
+    let distance = hit.t * hit_record.get_ray().direction.length();
+    let tint = Color::new(
+        (-absorption.r * distance).exp(),
+        (-absorption.g * distance).exp(),
+        (-absorption.b * distance).exp(),
+    );
+    Box::new(TintedBSDF::new(bsdf, tint))
 }
 
 pub struct Lambertian;
 
 impl Material for Lambertian {
-    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas) {
-        hit_record.set_scatter_pdf(CosineHemisphere::new(hit_record.get_hit().normal));
-        hit_record.get_hit_mut().attenuation = atlas.get_attenuation(hit_record.get_hit());
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        let hit = hit_record.get_hit();
+        Some(Box::new(LambertianBSDF::new(
+            atlas.get_attenuation(hit),
+            hit.normal,
+        )))
     }
 }
 
@@ -31,55 +91,150 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas) {
-        let reflected = hit_record
-            .get_ray()
-            .direction
-            .reflect(hit_record.get_hit().normal);
-        let reflected = reflected.normalize() + Vec3::random_unit_vector() * self.fuzz;
-        if reflected.dot(hit_record.get_hit().normal) > 0.0 {
-            hit_record.set_scatter_ray(reflected);
-            hit_record.get_hit_mut().attenuation = atlas.get_attenuation(hit_record.get_hit());
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        let hit = hit_record.get_hit();
+        Some(Box::new(MetalBSDF::new(
+            atlas.get_attenuation(hit),
+            hit.normal,
+            self.fuzz,
+        )))
+    }
+}
+
+// a physically based metal. eta and k are the complex index of refraction per channel, and the
+// roughness in [0, 1] spreads the reflection with a ggx microfacet distribution. a roughness
+// texture in the atlas scales it
+pub struct Conductor {
+    eta: Color,
+    k: Color,
+    roughness: f64,
+}
+
+impl Conductor {
+    pub fn new(eta: Color, k: Color, roughness: f64) -> Self {
+        Self { eta, k, roughness }
+    }
+
+    pub fn gold(roughness: f64) -> Self {
+        Self::new(
+            Color::new(0.143, 0.374, 1.442),
+            Color::new(3.983, 2.385, 1.603),
+            roughness,
+        )
+    }
+
+    pub fn copper(roughness: f64) -> Self {
+        Self::new(
+            Color::new(0.200, 0.924, 1.102),
+            Color::new(3.912, 2.452, 2.142),
+            roughness,
+        )
+    }
+
+    pub fn silver(roughness: f64) -> Self {
+        Self::new(
+            Color::new(0.155, 0.117, 0.138),
+            Color::new(4.828, 3.122, 2.147),
+            roughness,
+        )
+    }
+
+    pub fn aluminium(roughness: f64) -> Self {
+        Self::new(
+            Color::new(1.657, 0.880, 0.521),
+            Color::new(9.224, 6.270, 4.837),
+            roughness,
+        )
+    }
+
+    // one of the metals above by name
+    pub fn preset(name: &str, roughness: f64) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gold" => Some(Self::gold(roughness)),
+            "copper" => Some(Self::copper(roughness)),
+            "silver" => Some(Self::silver(roughness)),
+            "aluminium" | "aluminum" => Some(Self::aluminium(roughness)),
+            _ => None,
         }
     }
 }
 
+impl Material for Conductor {
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        Some(Box::new(ConductorBSDF::new(
+            hit_record.get_hit().normal,
+            GGX::new(self.roughness * atlas.get_roughness(hit_record.get_hit())),
+            self.eta,
+            self.k,
+        )))
+    }
+}
+
+// glass. light inside it may be absorbed, which tints it
 pub struct Dielectric {
     refraction_index: f64,
+    absorption: Option<Color>,
 }
 
 impl Dielectric {
     pub fn new(refraction_index: f64) -> Self {
-        Self { refraction_index }
+        Self {
+            refraction_index,
+            absorption: None,
+        }
     }
 
-    fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
-        let r0 = ((1.0 - refraction_index) / (1.0 + refraction_index)).powi(2);
-        r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+    // the share of each channel absorbed per unit of distance inside. clear by default, unless
+    // the atlas has an absorption texture
+    pub fn set_absorption(mut self, absorption: Color) -> Self {
+        self.absorption = Some(absorption);
+        self
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, hit_record: &mut HitRecord, _atlas: &Atlas) {
-        let refraction_ratio = if hit_record.get_hit().front_face {
-            1.0 / self.refraction_index
-        } else {
-            self.refraction_index
-        };
-        let unit_direction = hit_record.get_ray().direction.normalize();
-        let normal = hit_record.get_hit().normal;
-        let cos_theta = (-unit_direction).dot(normal);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-        let direction = if refraction_ratio * sin_theta > 1.0
-            || Self::reflectance(cos_theta, refraction_ratio) > rand::random()
-        {
-            unit_direction.reflect(normal)
-        } else {
-            let r_out_perp = (unit_direction + normal * cos_theta) * refraction_ratio;
-            let r_out_parallel = -normal * (1.0 - r_out_perp.length_squared()).sqrt();
-            r_out_perp + r_out_parallel
-        };
-        hit_record.set_scatter_ray(direction);
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        let bsdf = Box::new(DielectricBSDF::new(
+            hit_record.get_hit().normal,
+            refraction_ratio(hit_record, self.refraction_index),
+        ));
+        Some(absorb(bsdf, hit_record, atlas, self.absorption))
+    }
+}
+
+// frosted glass. like Dielectric, with a ggx microfacet surface of the given roughness in [0, 1],
+// scaled by a roughness texture in the atlas
+pub struct RoughDielectric {
+    refraction_index: f64,
+    roughness: f64,
+    absorption: Option<Color>,
+}
+
+impl RoughDielectric {
+    pub fn new(refraction_index: f64, roughness: f64) -> Self {
+        Self {
+            refraction_index,
+            roughness,
+            absorption: None,
+        }
+    }
+
+    // as in Dielectric
+    pub fn set_absorption(mut self, absorption: Color) -> Self {
+        self.absorption = Some(absorption);
+        self
+    }
+}
+
+impl Material for RoughDielectric {
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        let hit = hit_record.get_hit();
+        let bsdf = Box::new(RoughDielectricBSDF::new(
+            hit.normal,
+            GGX::new(self.roughness * atlas.get_roughness(hit)),
+            1.0 / refraction_ratio(hit_record, self.refraction_index),
+        ));
+        Some(absorb(bsdf, hit_record, atlas, self.absorption))
     }
 }
 
@@ -94,15 +249,23 @@ impl Isotropic {
 }
 
 impl Material for Isotropic {
-    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas) {
-        if rand::random::<f64>() > self.glow {
-            hit_record.set_scatter_pdf(UniformSphere);
-            hit_record.get_hit_mut().attenuation = atlas.get_attenuation(hit_record.get_hit());
+    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) {
+        if sampler.get_1d() > self.glow {
+            hit_record.set_scatter_bsdf(IsotropicBSDF::new(
+                atlas.get_attenuation(hit_record.get_hit()),
+            ));
         } else {
             hit_record.set_scatter_absorb();
             hit_record.get_hit_mut().emission = atlas.get_attenuation(hit_record.get_hit());
         }
     }
+
+    // the phase function when the medium scatters rather than glows
+    fn bsdf(&self, hit_record: &HitRecord, atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        Some(Box::new(IsotropicBSDF::new(
+            atlas.get_attenuation(hit_record.get_hit()),
+        )))
+    }
 }
 
 pub struct Translucent {
@@ -116,29 +279,11 @@ impl Translucent {
 }
 
 impl Material for Translucent {
-    fn scatter(&self, hit_record: &mut HitRecord, _atlas: &Atlas) {
-        let refraction_ratio = if hit_record.get_hit().front_face {
-            1.0 / self.refraction_index
-        } else {
-            self.refraction_index
-        };
-        let unit_direction = hit_record.get_ray().direction.normalize();
-        let normal = hit_record.get_hit().normal;
-        let cos_theta = (-unit_direction).dot(normal);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-        let mut direction = unit_direction.reflect(normal);
-        if refraction_ratio * sin_theta <= 1.0 {
-            let reflect_color = Color::new(1.0, sin_theta, 0.0);
-            if rand::random::<bool>() {
-                let r_out_perp = (unit_direction + normal * cos_theta) * refraction_ratio;
-                let r_out_parallel = -normal * (1.0 - r_out_perp.length_squared()).sqrt();
-                direction = r_out_perp + r_out_parallel;
-                hit_record.get_hit_mut().attenuation = (Color::WHITE - reflect_color) * 2.0;
-            } else {
-                hit_record.get_hit_mut().attenuation = reflect_color * 2.0;
-            }
-        };
-        hit_record.set_scatter_ray(direction);
+    fn bsdf(&self, hit_record: &HitRecord, _atlas: &Atlas) -> Option<Box<dyn BSDF>> {
+        Some(Box::new(TranslucentBSDF::new(
+            hit_record.get_hit().normal,
+            refraction_ratio(hit_record, self.refraction_index),
+        )))
     }
 }
 
@@ -153,9 +298,63 @@ impl Emissive {
 }
 
 impl Material for Emissive {
-    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas) {
+    fn scatter(&self, hit_record: &mut HitRecord, atlas: &Atlas, _sampler: &mut dyn Sampler) {
         if hit_record.get_hit().front_face {
             hit_record.get_hit_mut().emission = atlas.get_emission(hit_record.get_hit()) * self.ratio;
         }
     }
 }
+
+#[test]
+fn test_absorption() {
+    use crate::ray::Ray;
+    use crate::sampler::IndependentSampler;
+    use crate::shape::{Shape, Sphere};
+    use crate::texture::SolidColor;
+    use crate::vec3::Vec3;
+
+    // the weight of scattering where ray leaves or enters a sphere of radius 2
+    fn weight(material: &dyn Material, atlas: &Atlas, ray: Ray) -> Color {
+        let mut sampler = IndependentSampler::new(0);
+        let mut hit_record = HitRecord::new(ray);
+        assert!(Sphere::new(Vec3::default(), 2.0).hit(&mut hit_record, atlas, &mut sampler));
+        let bsdf = material.bsdf(&hit_record, atlas).unwrap();
+        let outgoing = -hit_record.get_ray().direction.normalize();
+        bsdf.sample(outgoing, &mut sampler).unwrap().weight
+    }
+    let close = |a: Color, b: Color| {
+        [a.r - b.r, a.g - b.g, a.b - b.b]
+            .iter()
+            .all(|d| d.abs() < 1e-9)
+    };
+    // leaving from the center crosses 2 units. t is 4 along the shorter direction
+    let inside = Ray::new(Vec3::default(), Vec3::new(0.5, 0.0, 0.0));
+    let outside = Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+    let clear = Atlas::default();
+    let textured = || Atlas::default().set_absorption(SolidColor::new(Color::gray(0.5)));
+    let constant = Dielectric::new(1.5).set_absorption(Color::new(0.1, 0.2, 0.3));
+    let expected = Color::new((-0.2f64).exp(), (-0.4f64).exp(), (-0.6f64).exp());
+    assert!(close(weight(&constant, &clear, inside.clone()), expected));
+    // light entering hasn't crossed the glass yet
+    assert!(close(
+        weight(&constant, &clear, outside.clone()),
+        Color::WHITE
+    ));
+    // the texture alone, and scaling the constant
+    let alone = Color::gray((-1.0f64).exp());
+    assert!(close(
+        weight(&Dielectric::new(1.5), &textured(), inside.clone()),
+        alone
+    ));
+    let rough = RoughDielectric::new(1.5, 0.0);
+    assert!(close(weight(&rough, &textured(), inside.clone()), alone));
+    let scaled = Color::new((-0.1f64).exp(), (-0.2f64).exp(), (-0.3f64).exp());
+    assert!(close(
+        weight(&constant, &textured(), inside.clone()),
+        scaled
+    ));
+    assert!(close(
+        weight(&Dielectric::new(1.5), &clear, inside),
+        Color::WHITE
+    ));
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::aabb::Aabb;
-use crate::bvh::ShapeTree;
+use crate::bvh::{ShapeTree, SplitStrategy};
 use crate::hit_record::HitRecord;
 use crate::onb::Onb;
+use crate::sampler::Sampler;
 use crate::shape::Shape;
 use crate::texture::{Atlas, UV};
 use crate::vec3::Vec3;
@@ -79,7 +81,7 @@ impl Triangle {
 }
 
 impl Shape for Triangle {
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, _sampler: &mut dyn Sampler) -> bool {
         let ray = hit_record.get_ray();
         let denominator = self.normal.dot(ray.direction);
         let t = (self.d - self.normal.dot(ray.origin)) / denominator;
@@ -111,12 +113,17 @@ impl Shape for Triangle {
 
 pub struct Mesh {
     pub shapes: HashMap<String, Vec<Triangle>>,
+    // bottom-level trees that were already built. every instance of a sub-mesh shares one
+    blas: HashMap<String, Arc<ShapeTree<Triangle>>>,
 }
 
 impl Mesh {
     pub fn load_obj(path: &str) -> Self {
-        let obj = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS);
-        let (models, _materials) = obj.unwrap();
+        Self::open(path).expect("Cannot load the obj file")
+    }
+
+    pub fn open(path: &str) -> Result<Self, tobj::LoadError> {
+        let (models, _materials) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)?;
         let mut map = HashMap::default();
         for model in models {
             let mut triangles = Vec::default();
@@ -169,15 +176,88 @@ impl Mesh {
             });
             map.insert(model.name, triangles);
         }
-        Self { shapes: map }
+        Ok(Self {
+            shapes: map,
+            blas: HashMap::default(),
+        })
     }
 
-    pub fn remove_shape(&mut self, key: &str) -> ShapeTree {
-        let vec = self.shapes.remove(key).unwrap();
-        ShapeTree::new(vec.into_iter().map(|t| Box::new(t) as Box<dyn Shape>).collect())
+    // take a sub-mesh out with a tree of its own. None if there's no such sub-mesh
+    pub fn remove_shape(
+        &mut self,
+        key: &str,
+        strategy: SplitStrategy,
+    ) -> Option<ShapeTree<Triangle>> {
+        let triangles = self.shapes.remove(key)?;
+        Some(ShapeTree::with_strategy(triangles, strategy))
+    }
+
+    // build the tree of a sub-mesh on first use and share it afterwards. the strategy only matters
+    // for the first call
+    pub fn blas(&mut self, key: &str, strategy: SplitStrategy) -> Option<Arc<ShapeTree<Triangle>>> {
+        if let Some(blas) = self.blas.get(key) {
+            return Some(blas.clone());
+        }
+        let blas = Arc::new(self.remove_shape(key, strategy)?);
+        self.blas.insert(key.to_string(), blas.clone());
+        Some(blas)
     }
 
     pub fn get_names(&self) -> Vec<&String> {
-        self.shapes.keys().collect()
+        self.shapes.keys().chain(self.blas.keys()).collect()
     }
 }
+
+#[test]
+fn test_instances() {
+    use crate::ray::Ray;
+    use crate::sampler::IndependentSampler;
+    use crate::shape::{Instance, Transformed};
+    use crate::transform::Transform;
+
+    let triangle = |transform: Transform| {
+        let normal = transform
+            .normal_matrix()
+            .direction(Vec3::new(0.0, 0.0, 1.0));
+        Triangle::vertex(
+            transform.pos(Vec3::new(-1.0, -1.0, 0.0)),
+            transform.pos(Vec3::new(1.0, -1.0, 0.0)),
+            transform.pos(Vec3::new(0.0, 1.0, 0.0)),
+            UV::new(0.0, 0.0),
+            UV::new(1.0, 0.0),
+            UV::new(0.5, 1.0),
+            normal,
+            normal,
+            normal,
+        )
+    };
+    let mut mesh = Mesh {
+        shapes: HashMap::from([("triangle".to_string(), vec![triangle(Transform::default())])]),
+        blas: HashMap::default(),
+    };
+    // every instance shares the tree built the first time
+    let first = mesh.blas("triangle", SplitStrategy::Sah).unwrap();
+    let second = mesh.blas("triangle", SplitStrategy::Median).unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(Arc::strong_count(&first), 3);
+    assert!(mesh.blas("missing", SplitStrategy::Sah).is_none());
+    assert!(mesh.remove_shape("triangle", SplitStrategy::Sah).is_none());
+
+    // an instance is hit where the triangle moved by its transform would be
+    let transform = Transform::translate(Vec3::new(0.5, 0.0, -3.0))
+        * Transform::rotate_y(30.0)
+        * Transform::scale(Vec3::new(2.0, 1.0, 1.0));
+    let instance: Instance<ShapeTree<Triangle>> = Transformed::new(transform, first);
+    let moved = triangle(transform);
+    let mut sampler = IndependentSampler::new(0);
+    let atlas = Atlas::default();
+    let ray = Ray::new(Vec3::default(), Vec3::new(0.1, 0.1, -1.0));
+    let mut instance_hit = HitRecord::new(ray.clone());
+    let mut moved_hit = HitRecord::new(ray);
+    assert!(instance.hit(&mut instance_hit, &atlas, &mut sampler));
+    assert!(moved.hit(&mut moved_hit, &atlas, &mut sampler));
+    let (a, b) = (instance_hit.get_hit(), moved_hit.get_hit());
+    assert!((a.t - b.t).abs() < 1e-9);
+    assert!((a.position - b.position).length() < 1e-9);
+    assert!((a.normal - b.normal.normalize()).length() < 1e-9);
+}
@@ -0,0 +1,169 @@
+use std::f64::consts::PI;
+
+use crate::color::Color;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// the ggx (trowbridge-reitz) distribution of microfacet normals with smith masking-shadowing.
+// directions are in a local frame where the surface normal is z
+#[derive(Clone, Copy, Debug)]
+pub struct GGX {
+    alpha: f64,
+}
+
+impl GGX {
+    // below this, the surface is treated as perfectly smooth
+    const MIN_ALPHA: f64 = 1e-3;
+
+    // alpha is the square of the roughness, which looks more even
+    pub fn new(roughness: f64) -> Self {
+        Self {
+            alpha: roughness.clamp(0.0, 1.0).powi(2),
+        }
+    }
+
+    // a delta lobe. the distribution functions shouldn't be used
+    pub fn is_smooth(&self) -> bool {
+        self.alpha < Self::MIN_ALPHA
+    }
+
+    // the density of microfacets with normal h, per area of the surface and solid angle
+    pub fn d(&self, h: Vec3) -> f64 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        let t = h.z * h.z * (a2 - 1.0) + 1.0;
+        a2 / (PI * t * t)
+    }
+
+    fn lambda(&self, w: Vec3) -> f64 {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0.0 {
+            return f64::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0) / 2.0
+    }
+
+    // the share of microfacets seen from w
+    pub fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // the share of microfacets seen from both directions
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // the density of sample_visible choosing h seen from w
+    pub fn visible_prob(&self, w: Vec3, h: Vec3) -> f64 {
+        if w.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(w) / w.z * self.d(h) * w.dot(h).max(0.0)
+    }
+
+    // sample a microfacet normal in proportion to how much of it is seen from w, which must be
+    // above the surface. from "sampling the ggx distribution of visible normals", heitz 2018
+    pub fn sample_visible(&self, w: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        // stretch to the hemisphere configuration
+        let wh = Vec3::new(self.alpha * w.x, self.alpha * w.y, w.z).normalize();
+        let t1 = if wh.z < 0.99999 {
+            (Vec3::new(0.0, 0.0, 1.0) * wh).normalize()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = wh * t1;
+        // a point on the disk, squeezed towards the visible half
+        let (u1, u2) = sampler.get_2d();
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + wh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + wh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        // unstretch
+        Vec3::new(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(1e-6)).normalize()
+    }
+}
+
+// the reflectance of a conductor with complex index of refraction eta + ik, lit at cosine to the
+// normal from outside
+pub fn fresnel_conductor(cosine: f64, eta: Color, k: Color) -> Color {
+    Color::new(
+        fresnel_conductor_channel(cosine, eta.r, k.r),
+        fresnel_conductor_channel(cosine, eta.g, k.g),
+        fresnel_conductor_channel(cosine, eta.b, k.b),
+    )
+}
+
+// the reflectance of the boundary between dielectrics, lit at cosine to the normal from the side
+// the normal points to. eta is the index of refraction of the other side over that of this side
+pub fn fresnel_dielectric(cosine: f64, eta: f64) -> f64 {
+    let cos_i = cosine.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    // total internal reflection
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+fn fresnel_conductor_channel(cosine: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cosine.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    // perpendicular
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cosine.clamp(0.0, 1.0) * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    // parallel
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
+}
+
+#[test]
+fn test_ggx() {
+    use crate::sampler::IndependentSampler;
+
+    let ggx = GGX::new(0.5);
+    // the projected microfacet area is the macro surface
+    let steps = 100000;
+    let mut integral = 0.0;
+    for i in 0..steps {
+        let theta = (i as f64 + 0.5) / steps as f64 * PI / 2.0;
+        let h = Vec3::new(theta.sin(), 0.0, theta.cos());
+        integral += ggx.d(h) * h.z * 2.0 * PI * theta.sin() * PI / 2.0 / steps as f64;
+    }
+    assert!((integral - 1.0).abs() < 1e-3, "{}", integral);
+    // visible normals face the viewer, and their density integrates to one and matches the
+    // samples, here by the mean tilt towards the viewer
+    let mut sampler = IndependentSampler::new(0);
+    let w = Vec3::new(0.6, 0.0, 0.8);
+    let n = 200000;
+    let (mut integral, mut tilt, mut sampled_tilt) = (0.0, 0.0, 0.0);
+    for _ in 0..n {
+        let h = ggx.sample_visible(w, &mut sampler);
+        assert!(w.dot(h) >= 0.0);
+        sampled_tilt += h.x / n as f64;
+        let h = Vec3::random_unit_vector(&mut sampler);
+        let prob = ggx.visible_prob(w, h) * 4.0 * PI / n as f64;
+        integral += prob;
+        tilt += h.x * prob;
+    }
+    assert!((integral - 1.0).abs() < 0.02, "{}", integral);
+    assert!(
+        (tilt - sampled_tilt).abs() < 0.01,
+        "{} {}",
+        tilt,
+        sampled_tilt
+    );
+}
@@ -1,5 +1,5 @@
+use crate::sampler::{IndependentSampler, Sampler};
 use crate::vec3::Vec3;
-use rand::Rng;
 pub struct Noise {
     random_data: [Vec3; Noise::POINT_COUNT],
     perm_x: [usize; Noise::POINT_COUNT],
@@ -9,21 +9,26 @@ pub struct Noise {
 
 impl Default for Noise {
     fn default() -> Self {
+        Self::new(&mut IndependentSampler::new(0))
+    }
+}
+
+impl Noise {
+    const POINT_COUNT: usize = 256;
+
+    // the same sampler state gives the same noise
+    pub fn new(sampler: &mut dyn Sampler) -> Self {
         let mut random_data = [Vec3::default(); Self::POINT_COUNT];
         for i in random_data.iter_mut() {
-            *i = Vec3::random_in_cube().normalize();
+            *i = Vec3::random_in_cube(sampler).normalize();
         }
         Self {
             random_data,
-            perm_x: Self::generate_perm(),
-            perm_y: Self::generate_perm(),
-            perm_z: Self::generate_perm(),
+            perm_x: Self::generate_perm(sampler),
+            perm_y: Self::generate_perm(sampler),
+            perm_z: Self::generate_perm(sampler),
         }
     }
-}
-
-impl Noise {
-    const POINT_COUNT: usize = 256;
 
     pub fn noise(&self, p: Vec3) -> f64 {
         let u = p.x - p.x.floor();
@@ -59,19 +64,18 @@ impl Noise {
         accum.abs()
     }
 
-    fn generate_perm() -> [usize; Noise::POINT_COUNT] {
+    fn generate_perm(sampler: &mut dyn Sampler) -> [usize; Noise::POINT_COUNT] {
         let mut perm = [0; Self::POINT_COUNT];
         for (i, item) in perm.iter_mut().enumerate() {
             *item = i;
         }
-        Self::permute(&mut perm);
+        Self::permute(&mut perm, sampler);
         perm
     }
 
-    fn permute(perm: &mut [usize]) {
-        let mut rng = rand::thread_rng();
+    fn permute(perm: &mut [usize], sampler: &mut dyn Sampler) {
         for i in (1..perm.len()).rev() {
-            let target = rng.gen_range(0..i);
+            let target = (sampler.get_1d() * i as f64) as usize;
             perm.swap(i, target);
         }
     }
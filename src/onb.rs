@@ -37,4 +37,9 @@ impl Onb {
     pub fn local(&self, a: Vec3) -> Vec3 {
         self.u * a.x + self.v * a.y + self.w * a.z
     }
+
+    // the inverse of local: the coordinates of a along u, v and w
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3::new(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+    }
 }
@@ -1,7 +1,11 @@
+use crate::hit_record::HitRecord;
+use crate::interval::Interval;
 use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::shape::ShapePDFProvider;
+use crate::texture::Atlas;
 use crate::vec3::Vec3;
-use rand::prelude::IndexedRandom;
 use std::f64::consts::PI;
 use std::fmt::Debug;
 
@@ -9,7 +13,18 @@ pub trait PDF: Debug {
     // return the probability density function value for a given Vec3
     fn prob(&self, direction: Vec3) -> f64;
     // generate a random Vec3 according to the probability density function
-    fn generate(&self) -> Vec3;
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3;
+}
+
+// the multiple importance sampling weight of a sample drawn with density a, when density b could
+// have produced it too
+pub fn power_heuristic(a: f64, b: f64) -> f64 {
+    let (a, b) = (a * a, b * b);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
 }
 
 // a dummy PDF stating that the PDF is empty
@@ -20,7 +35,7 @@ impl PDF for EmptyPDF {
     fn prob(&self, _direction: Vec3) -> f64 {
         unimplemented!()
     }
-    fn generate(&self) -> Vec3 {
+    fn generate(&self, _sampler: &mut dyn Sampler) -> Vec3 {
         unimplemented!()
     }
 }
@@ -40,8 +55,8 @@ impl PDF for UniformHemisphere {
     fn prob(&self, _direction: Vec3) -> f64 {
         1.0 / (2.0 * PI)
     }
-    fn generate(&self) -> Vec3 {
-        Vec3::random_unit_on_hemisphere(self.normal)
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::random_unit_on_hemisphere(self.normal, sampler)
     }
 }
 
@@ -63,8 +78,8 @@ impl PDF for CosineHemisphere {
         let cosine = direction.normalize().dot(self.uvw.w);
         (cosine / PI).max(0.0)
     }
-    fn generate(&self) -> Vec3 {
-        self.uvw.local(Vec3::random_cosine_direction())
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        self.uvw.local(Vec3::random_cosine_direction(sampler))
     }
 }
 
@@ -75,8 +90,8 @@ impl PDF for UniformSphere {
     fn prob(&self, _direction: Vec3) -> f64 {
         1.0 / (4.0 * PI)
     }
-    fn generate(&self) -> Vec3 {
-        Vec3::random_unit_vector()
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::random_unit_vector(sampler)
     }
 }
 
@@ -104,10 +119,50 @@ impl ShapePDF {
         sum
     }
 
-    pub fn generate(&self, origin: Vec3) -> Vec3 {
-        self.pdfs
-            .choose(&mut rand::thread_rng())
-            .unwrap()
-            .generate(origin)
+    pub fn generate(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let index = (sampler.get_1d() * self.pdfs.len() as f64) as usize;
+        self.pdfs[index.min(self.pdfs.len() - 1)].generate(origin, sampler)
+    }
+
+    // pick a light and a point on it uniformly by area.
+    // return the point, the outward normal and the probability density by area
+    pub fn sample_area(&self, sampler: &mut dyn Sampler) -> (Vec3, Vec3, f64) {
+        let index = (sampler.get_1d() * self.pdfs.len() as f64) as usize;
+        let pdf = &self.pdfs[index.min(self.pdfs.len() - 1)];
+        let (position, normal) = pdf.sample_area(sampler);
+        (
+            position,
+            normal,
+            1.0 / (self.pdfs.len() as f64 * pdf.area()),
+        )
+    }
+
+    // the distance along ray to the nearest light, e.g. to the point generate chose. None if it
+    // misses them
+    pub fn hit_distance(&self, ray: &Ray, sampler: &mut dyn Sampler) -> Option<f64> {
+        let atlas = Atlas::default();
+        let mut hit_record = HitRecord::new(ray.clone());
+        let mut distance = None;
+        for pdf in &self.pdfs {
+            // a hit narrows the interval, so the last one is the nearest
+            if pdf.hit(&mut hit_record, &atlas, sampler) {
+                distance = Some(hit_record.get_hit().t);
+            }
+        }
+        distance
+    }
+
+    // the density by area of sample_area choosing the point at t along ray. lights may overlap
+    pub fn area_prob(&self, ray: &Ray, t: f64, sampler: &mut dyn Sampler) -> f64 {
+        let atlas = Atlas::default();
+        let mut sum = 0.0;
+        for pdf in &self.pdfs {
+            let mut hit_record = HitRecord::new(ray.clone());
+            hit_record.set_interval(Interval::new(t - Interval::DELTA, t + Interval::DELTA));
+            if pdf.hit(&mut hit_record, &atlas, sampler) {
+                sum += 1.0 / (self.pdfs.len() as f64 * pdf.area());
+            }
+        }
+        sum
     }
 }
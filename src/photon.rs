@@ -0,0 +1,370 @@
+use std::f64::consts::PI;
+
+use crate::color::Color;
+use crate::hit_record::HitRecord;
+use crate::hittable::{Hittable, World};
+use crate::integrator::{Integrator, PathTracer};
+use crate::onb::Onb;
+use crate::pdf::power_heuristic;
+use crate::ray::Ray;
+use crate::sampler::{IndependentSampler, Sampler};
+use crate::vec3::Vec3;
+
+// a photon that reached a diffuse or glossy surface
+#[derive(Clone, Copy, Debug)]
+struct Photon {
+    position: Vec3,
+    // the unit direction it travelled in
+    direction: Vec3,
+    power: Color,
+    // the axis its node splits at
+    axis: usize,
+}
+
+// a kd-tree over photons. it is stored implicitly: the median of a range is the node and the
+// halves on either side are its children
+struct PhotonMap {
+    photons: Vec<Photon>,
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>) -> Self {
+        Self::build(&mut photons);
+        Self { photons }
+    }
+
+    fn build(photons: &mut [Photon]) {
+        if photons.len() <= 1 {
+            return;
+        }
+        // split along the widest extent of the photons
+        let mut min = photons[0].position;
+        let mut max = min;
+        for photon in photons.iter() {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(photon.position[axis]);
+                max[axis] = max[axis].max(photon.position[axis]);
+            }
+        }
+        let axis = (0..3)
+            .max_by(|&a, &b| (max[a] - min[a]).total_cmp(&(max[b] - min[b])))
+            .unwrap();
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| a.position[axis].total_cmp(&b.position[axis]));
+        photons[mid].axis = axis;
+        let (lower, upper) = photons.split_at_mut(mid);
+        Self::build(lower);
+        Self::build(&mut upper[1..]);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // call f on every photon within radius of position
+    fn gather<F: FnMut(&Photon)>(&self, position: Vec3, radius: f64, mut f: F) {
+        Self::visit(&self.photons, position, radius * radius, &mut f);
+    }
+
+    fn visit<F: FnMut(&Photon)>(
+        photons: &[Photon],
+        position: Vec3,
+        radius_squared: f64,
+        f: &mut F,
+    ) {
+        if photons.is_empty() {
+            return;
+        }
+        let mid = photons.len() / 2;
+        let photon = &photons[mid];
+        let offset = position[photon.axis] - photon.position[photon.axis];
+        let (near, far) = if offset < 0.0 {
+            (&photons[..mid], &photons[mid + 1..])
+        } else {
+            (&photons[mid + 1..], &photons[..mid])
+        };
+        Self::visit(near, position, radius_squared, f);
+        if (photon.position - position).length_squared() <= radius_squared {
+            f(photon);
+        }
+        if offset * offset <= radius_squared {
+            Self::visit(far, position, radius_squared, f);
+        }
+    }
+}
+
+// path tracing with the caustics taken from a photon map. before rendering, photons are shot from
+// the lights, and those that reach a diffuse surface after one or more specular bounces (through
+// glass or off metal) are stored. a path adds up the photons around each of its diffuse hits
+// instead of finding lights through specular chains itself, which it rarely does
+pub struct PhotonMapper {
+    max_depth: u32,
+    // bounces before russian roulette may end a path or a photon
+    roulette_depth: u32,
+    photons: u32,
+    // the radius photons are gathered in. None picks one from the size of the scene
+    radius: Option<f64>,
+    caustics: PhotonMap,
+}
+
+impl PhotonMapper {
+    pub const DEFAULT_PHOTONS: u32 = 200_000;
+    // the gather radius relative to the diagonal of the scene
+    const RELATIVE_RADIUS: f64 = 0.002;
+    // the sampler stream photons are drawn from
+    const PHOTON_STREAM: u32 = 0;
+
+    pub fn new(max_depth: u32) -> Self {
+        Self {
+            max_depth,
+            roulette_depth: PathTracer::DEFAULT_ROULETTE_DEPTH,
+            photons: Self::DEFAULT_PHOTONS,
+            radius: None,
+            caustics: PhotonMap::new(Vec::new()),
+        }
+    }
+
+    pub fn set_roulette_depth(mut self, depth: u32) -> Self {
+        self.roulette_depth = depth;
+        self
+    }
+
+    // the number of photons shot from the lights. more photons allow a smaller radius
+    pub fn set_photons(mut self, photons: u32) -> Self {
+        self.photons = photons;
+        self
+    }
+
+    // a larger radius blurs the caustics but needs fewer photons
+    pub fn set_radius(mut self, radius: f64) -> Self {
+        self.radius = Some(radius);
+        self
+    }
+
+    fn radius(&self, world: &World) -> f64 {
+        self.radius.unwrap_or_else(|| {
+            let aabb = world.objects.bounding_box();
+            (aabb.max_pos() - aabb.min_pos()).length() * Self::RELATIVE_RADIUS
+        })
+    }
+
+    // follow a photon from a point on the lights and store it if it lands on a diffuse surface
+    // after specular bounces. power is the share of each photon
+    fn trace_photon(
+        &self,
+        world: &World,
+        power: f64,
+        photons: &mut Vec<Photon>,
+        sampler: &mut dyn Sampler,
+    ) {
+        let time = sampler.get_1d();
+        let Some((position, normal, emission, prob)) = world.sample_emitter(time, sampler) else {
+            return;
+        };
+        // emit with a cosine distribution, which cancels with the cosine of the emission
+        let direction = Onb::normal(normal).local(Vec3::random_cosine_direction(sampler));
+        let mut ray = Ray::with_time(position, direction, time);
+        let mut power = emission * (PI * power / prob);
+        let mut specular = false;
+        for depth in 0..self.max_depth {
+            let mut hit_record = HitRecord::new(ray);
+            if !world.objects.hit(&mut hit_record, sampler) {
+                return;
+            }
+            let hit = hit_record.get_hit();
+            // glossy and diffuse surfaces end the photon. media have no normal and are left to
+            // the path tracer
+            if !hit_record.is_delta() {
+                if specular && hit.normal.length_squared() > 0.0 {
+                    photons.push(Photon {
+                        position: hit.position,
+                        direction: hit_record.get_ray().direction.normalize(),
+                        power,
+                        axis: 0,
+                    });
+                }
+                return;
+            }
+            let Some((next, attenuation, _)) = hit_record.sample_scatter(sampler) else {
+                return;
+            };
+            ray = next;
+            specular = true;
+            power = power * attenuation;
+            if depth >= self.roulette_depth {
+                let probability = attenuation.max_component().min(0.95);
+                if sampler.get_1d() >= probability {
+                    return;
+                }
+                power /= probability;
+            }
+        }
+    }
+
+    // the caustic radiance leaving a diffuse hit towards the ray, from the photons around it
+    fn gather(&self, hit_record: &HitRecord, radius: f64) -> Color {
+        let hit = hit_record.get_hit();
+        let mut sum = Color::BLACK;
+        self.caustics.gather(hit.position, radius, |photon| {
+            let direction = -photon.direction;
+            let cosine = hit.normal.dot(direction);
+            // photons on the other side of a thin surface
+            if cosine <= 0.0 {
+                return;
+            }
+            let value = hit_record.eval_scatter(direction).0;
+            sum += value * photon.power / cosine;
+        });
+        sum / (PI * radius * radius)
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn preprocess(&mut self, world: &World, seed: u64) {
+        let mut sampler = IndependentSampler::new(seed);
+        let power = 1.0 / self.photons.max(1) as f64;
+        let mut photons = Vec::new();
+        for index in 0..self.photons {
+            sampler.start_stream_sample(Self::PHOTON_STREAM, index);
+            self.trace_photon(world, power, &mut photons, &mut sampler);
+        }
+        self.caustics = PhotonMap::new(photons);
+        if self.radius.is_none() {
+            self.radius = Some(self.radius(world));
+        }
+    }
+
+    fn radiance(&self, world: &World, ray: Ray, sampler: &mut dyn Sampler) -> Color {
+        let radius = self.radius(world);
+        let mut radiance = Color::BLACK;
+        let mut throughput = Color::WHITE;
+        let mut ray = ray;
+        // as in PathTracer
+        let mut scatter_prob = None;
+        // whether the path has only bounced specularly since it left a diffuse surface. lights
+        // reached this way belong to the photon map
+        let mut caustic = false;
+        for depth in 0..self.max_depth {
+            let mut hit_record = HitRecord::new(ray);
+            let hit = world.objects.hit(&mut hit_record, sampler);
+            let weight = match scatter_prob {
+                Some(scatter_prob) if !world.light_pdf.empty() => {
+                    let ray = hit_record.get_ray();
+                    power_heuristic(
+                        scatter_prob,
+                        world.light_pdf.prob(ray.direction, ray.origin),
+                    )
+                }
+                _ => 1.0,
+            };
+            if !hit {
+                radiance += throughput * world.background(hit_record.get_ray()) * weight;
+                break;
+            }
+            let hit_info = hit_record.get_hit();
+            // photons only leave the lights, so other emitters still count
+            let counted = !caustic
+                || scatter_prob.is_some()
+                || world
+                    .light_pdf
+                    .area_prob(hit_record.get_ray(), hit_info.t, sampler)
+                    <= 0.0;
+            if counted {
+                radiance += throughput * hit_info.emission * weight;
+            }
+            if !hit_record.is_delta() {
+                let surface = hit_info.normal.length_squared() > 0.0;
+                if depth + 1 < self.max_depth {
+                    radiance += throughput * PathTracer::sample_light(world, &hit_record, sampler);
+                    if surface && !self.caustics.is_empty() {
+                        radiance += throughput * self.gather(&hit_record, radius);
+                    }
+                }
+                caustic = surface;
+            }
+            let Some((next, attenuation, prob)) = hit_record.sample_scatter(sampler) else {
+                break;
+            };
+            ray = next;
+            scatter_prob = prob;
+            throughput = throughput * attenuation;
+            if depth >= self.roulette_depth {
+                let probability = throughput.max_component().min(0.95);
+                if sampler.get_1d() >= probability {
+                    break;
+                }
+                throughput /= probability;
+            }
+        }
+        radiance
+    }
+}
+
+#[test]
+fn test_photon_map() {
+    let mut sampler = IndependentSampler::new(0);
+    let photons: Vec<Photon> = (0..1000)
+        .map(|_| Photon {
+            position: Vec3::random(-1.0, 1.0, &mut sampler),
+            direction: Vec3::default(),
+            power: Color::BLACK,
+            axis: 0,
+        })
+        .collect();
+    let map = PhotonMap::new(photons.clone());
+    let sorted = |positions: Vec<Vec3>| {
+        let mut positions: Vec<_> = positions.iter().map(|p| (p.x, p.y, p.z)).collect();
+        positions.sort_by(|a, b| a.0.total_cmp(&b.0));
+        positions
+    };
+    // the tree finds the same photons as looking at all of them
+    for _ in 0..20 {
+        let position = Vec3::random(-1.0, 1.0, &mut sampler);
+        let radius = sampler.get_1d() * 0.5;
+        let mut found = Vec::new();
+        map.gather(position, radius, |photon| found.push(photon.position));
+        let expected = photons
+            .iter()
+            .map(|photon| photon.position)
+            .filter(|p| (*p - position).length() <= radius)
+            .collect();
+        assert_eq!(sorted(found), sorted(expected));
+    }
+}
+
+#[test]
+fn test_caustics() {
+    use crate::hittable::lit_box;
+    use crate::material::Metal;
+    use crate::shape::Quad;
+    use crate::texture::Atlas;
+
+    // a mirror at x = -2 throws the light onto the floor as a caustic, which the path tracer only
+    // finds by scattering
+    let mut builder = lit_box(true);
+    let mirror = Quad::new(
+        Vec3::new(-2.0, 0.0, -2.0),
+        Vec3::new(0.0, 3.0, 0.0),
+        Vec3::new(0.0, 0.0, 4.0),
+    );
+    builder.add_object(mirror, Metal::new(0.0), Atlas::default());
+    let world = builder.build();
+    let ray = || Ray::new(Vec3::new(0.0, 0.5, 1.0), Vec3::new(0.0, -0.5, -1.0));
+    let mean = |integrator: &dyn Integrator, samples: u32| {
+        let mut sampler = IndependentSampler::new(0);
+        let mut sum = Color::BLACK;
+        for _ in 0..samples {
+            sum += integrator.radiance(&world, ray(), &mut sampler);
+        }
+        sum.g / samples as f64
+    };
+    let mut photon = PhotonMapper::new(4).set_photons(400000).set_radius(0.2);
+    photon.preprocess(&world, 0);
+    assert!(!photon.caustics.is_empty());
+    let with_caustics = mean(&photon, 20000);
+    let path = mean(&PathTracer::new(4), 200000);
+    // without photons, the mirrored light is missed
+    let without_caustics = mean(&PhotonMapper::new(4), 20000);
+    assert!(with_caustics > without_caustics * 1.02);
+    assert!((with_caustics / path - 1.0).abs() < 0.02);
+}
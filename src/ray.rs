@@ -8,11 +8,17 @@ pub struct Ray {
 }
 
 impl Ray {
+    // a ray at time 0
     pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self::with_time(origin, direction, 0.0)
+    }
+
+    // time is in [0, 1) and moves the Moving shapes
+    pub fn with_time(origin: Vec3, direction: Vec3, time: f64) -> Self {
         Self {
             origin,
             direction,
-            time: rand::random::<f64>(),
+            time,
         }
     }
 
@@ -1,133 +1,483 @@
-use std::sync::{Arc, Mutex};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use indicatif::ProgressBar;
 
 use crate::camera::Camera;
-use crate::canvas::Canvas;
+use crate::canvas::{Canvas, HdrCanvas};
+use crate::checkpoint::Checkpoint;
 use crate::color::Color;
-use crate::hit_record::HitRecord;
-use crate::hit_record::Scatter::{Absorb, ScatterPDF, ScatterRay};
-use crate::hittable::{Hittable, World};
-use crate::ray::Ray;
+use crate::hittable::World;
+use crate::integrator::Integrator;
+use crate::sampler::SamplerKind;
+use crate::tone_map::ToneMap;
+
+// a rectangle of pixels [x0, x1) * [y0, y1) rendered by one worker at a time
+struct Tile {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+impl Tile {
+    fn pixels(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (self.y0..self.y1).flat_map(move |j| (self.x0..self.x1).map(move |i| (i, j)))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SnapshotInterval {
+    Passes(u32),
+    Seconds(f64),
+}
+
+impl SnapshotInterval {
+    // whether to write after the given number of passes. restart the clock if so
+    fn due(&self, passes: u32, last: &mut Instant) -> bool {
+        let due = match *self {
+            SnapshotInterval::Passes(n) => passes.is_multiple_of(n.max(1)),
+            SnapshotInterval::Seconds(seconds) => {
+                last.elapsed() >= Duration::from_secs_f64(seconds)
+            }
+        };
+        if due {
+            *last = Instant::now();
+        }
+        due
+    }
+}
+
+// stop sampling a pixel once the standard error of its luminance falls below threshold times its mean.
+// the samples saved on converged pixels go to noisy ones, up to max_samples each,
+// until the total reaches the samples per pixel of the camera times the number of pixels, and no further
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSampling {
+    pub threshold: f64,
+    pub min_samples: u32,
+    pub max_samples: u32,
+}
 
 pub struct RayTracer {
     camera: Camera,
     canvas: Canvas,
+    film: HdrCanvas,
     world: World,
-    max_depth: u32,
+    integrator: Box<dyn Integrator>,
+    threads: u32,
+    scene: u64,
+    seed: u64,
+    sampler: SamplerKind,
+    tone_map: ToneMap,
+    // snapshot path and interval. if set, render one sample per pixel per pass
+    progressive: Option<(String, SnapshotInterval)>,
+    // checkpoint path and interval. also renders in passes
+    checkpoint: Option<(String, SnapshotInterval)>,
+    // the first sample index to render. non-zero when resumed
+    next_sample: u32,
+    adaptive: Option<AdaptiveSampling>,
 }
 
 impl RayTracer {
-    pub fn new(camera: Camera, canvas: Canvas, world: World, max_depth: u32) -> Self {
+    const TILE_SIZE: u32 = 32;
+
+    pub fn new<T: Integrator + 'static>(
+        camera: Camera,
+        canvas: Canvas,
+        world: World,
+        integrator: T,
+    ) -> Self {
         Self {
             camera,
+            film: HdrCanvas::empty(canvas.width(), canvas.height()),
             canvas,
             world,
-            max_depth,
+            integrator: Box::new(integrator),
+            threads: thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            scene: 0,
+            seed: 0,
+            sampler: SamplerKind::default(),
+            tone_map: ToneMap::default(),
+            progressive: None,
+            checkpoint: None,
+            next_sample: 0,
+            adaptive: None,
         }
     }
 
-    fn raytrace(&self, ray: Ray, left_depth: u32) -> Color {
-        if left_depth == 0 {
-            return Color::BLACK;
-        }
-        let mut hit_record = HitRecord::new(ray);
-        if !self.world.objects.hit(&mut hit_record) {
-            let a = 0.5 * (hit_record.get_ray().direction.normalize().y + 1.0);
-            return self.world.background * a;
-        }
-        let emission = hit_record.get_hit().emission;
-        let attenuation = hit_record.get_hit().attenuation;
-        match &hit_record.get_hit().scatter {
-            Absorb => hit_record.get_hit().emission,
-            ScatterRay(_) => {
-                self.raytrace(hit_record.move_hit().scatter.move_ray(), left_depth - 1)
-                    * attenuation
-                    + emission
-            }
-            ScatterPDF(_) => {
-                let (scatter, mixture_prob, scatter_prob) =
-                    hit_record.generate_scatter(&self.world.light_pdf);
-                self.raytrace(scatter, left_depth - 1) * attenuation * (scatter_prob / mixture_prob)
-                    + emission
+    // number of worker threads. defaults to the available parallelism
+    pub fn set_threads(mut self, threads: u32) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn set_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_sampler(mut self, sampler: SamplerKind) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    // how the radiance in the film is mapped to the 8-bit canvas
+    pub fn set_tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.tone_map = tone_map;
+        self
+    }
+
+    // render in passes of one sample per pixel and write the current average to path periodically.
+    // the snapshot is overwritten each time and may be an hdr format
+    pub fn set_progressive(mut self, path: &str, interval: SnapshotInterval) -> Self {
+        self.progressive = Some((path.to_string(), interval));
+        self
+    }
+
+    // periodically save the film, seed and sample progress so that the render can be resumed or merged.
+    // a final checkpoint is written when the render finishes
+    pub fn set_checkpoint(mut self, path: &str, interval: SnapshotInterval) -> Self {
+        self.checkpoint = Some((path.to_string(), interval));
+        self
+    }
+
+    // render in passes of one sample per pixel, skipping the pixels that have converged
+    pub fn set_adaptive(mut self, adaptive: AdaptiveSampling) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    // the scene the render is of, written to checkpoints. see Scene::id
+    pub fn set_scene(mut self, scene: u64) -> Self {
+        self.scene = scene;
+        self
+    }
+
+    // continue a render from its checkpoint. the scene, the image size, the sampler and the
+    // samples per pixel must match, so set them first
+    pub fn resume(mut self, checkpoint: Checkpoint) -> Result<Self, String> {
+        checkpoint.check_resume(
+            self.scene,
+            self.canvas.width(),
+            self.canvas.height(),
+            self.sampler,
+            self.camera.samples_per_pixel(),
+        )?;
+        self.seed = checkpoint.seed;
+        self.next_sample = checkpoint.next_sample;
+        self.film = checkpoint.film;
+        Ok(self)
+    }
+
+    fn tiles(&self) -> Vec<Tile> {
+        let width = self.canvas.width();
+        let height = self.canvas.height();
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(Self::TILE_SIZE as usize) {
+            for x in (0..width).step_by(Self::TILE_SIZE as usize) {
+                tiles.push(Tile {
+                    x0: x,
+                    y0: y,
+                    x1: (x + Self::TILE_SIZE).min(width),
+                    y1: (y + Self::TILE_SIZE).min(height),
+                });
             }
         }
+        tiles
     }
 
-    fn render_task(
-        progress_bar: Arc<ProgressBar>,
-        raytracer: Arc<Self>,
-        output: Arc<Mutex<Vec<Color>>>,
-        si: u32,
-        sj_length: u32,
-    ) {
-        let width = raytracer.canvas.width();
-        let height = raytracer.canvas.height();
-        let image_size = (width * height) as usize;
-        let mut result = vec![Color::BLACK; image_size];
-        for sj in 0..sj_length {
-            for i in 0..width {
-                for j in 0..height {
-                    let ray = raytracer.camera.get_ray_at(i, j, si, sj);
-                    let color = Self::raytrace(&raytracer, ray, raytracer.max_depth).fix();
-                    result[(i * height + j) as usize] += color;
+    // trace the given sample indices for every active pixel of the tile.
+    // return the radiance sums, squared luminance sums and sample counts in tile order
+    fn render_tile(
+        &self,
+        tile: &Tile,
+        samples: Range<u32>,
+        active: Option<&[bool]>,
+    ) -> Vec<(Color, f64, u32)> {
+        let width = self.canvas.width();
+        let mut sampler = self
+            .sampler
+            .create(self.seed, self.camera.samples_per_pixel());
+        tile.pixels()
+            .map(|(i, j)| {
+                if active.is_some_and(|active| !active[(j * width + i) as usize]) {
+                    return (Color::BLACK, 0.0, 0);
+                }
+                let mut sum = Color::BLACK;
+                let mut sum_sq = 0.0;
+                for sample in samples.clone() {
+                    sampler.start_pixel_sample((i, j), sample);
+                    let ray = self.camera.get_ray_at(i, j, sampler.as_mut());
+                    let color = self
+                        .integrator
+                        .radiance(&self.world, ray, sampler.as_mut())
+                        .fix();
+                    sum += color;
+                    sum_sq += color.luminance().powi(2);
                 }
+                (sum, sum_sq, samples.len() as u32)
+            })
+            .collect()
+    }
+
+    // workers keep taking the next tile until all tiles are done. only the tile is buffered per worker.
+    // if active is given, only the pixels marked in it are sampled
+    fn render_pass(
+        &self,
+        film: &Mutex<HdrCanvas>,
+        samples: Range<u32>,
+        active: Option<&[bool]>,
+        progress: &ProgressBar,
+    ) {
+        let tiles = self.tiles();
+        let next_tile = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(tiles.len() as u32) {
+                scope.spawn(|| loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    let Some(tile) = tiles.get(index) else {
+                        break;
+                    };
+                    let sums = self.render_tile(tile, samples.clone(), active);
+                    let mut film = film.lock().unwrap();
+                    for ((i, j), (sum, sum_sq, count)) in tile.pixels().zip(sums) {
+                        film.add(i, j, sum, sum_sq, count);
+                    }
+                    progress.inc(1);
+                });
+            }
+        });
+    }
+
+    fn snapshot(&self, film: &HdrCanvas, path: &str) {
+        if HdrCanvas::is_hdr_path(path) {
+            film.overwrite(path);
+        } else {
+            film.to_canvas(&self.tone_map).overwrite(path);
+        }
+    }
+
+    fn save_checkpoint(&self, film: &HdrCanvas, path: &str) {
+        let checkpoint = Checkpoint {
+            scene: self.scene,
+            seed: self.seed,
+            next_sample: self.next_sample,
+            sampler: self.sampler,
+            samples_per_pixel: self.camera.samples_per_pixel(),
+            film: film.clone(),
+        };
+        checkpoint
+            .save(path)
+            .unwrap_or_else(|e| eprintln!("Cannot save the checkpoint \"{}\": {}", path, e));
+    }
+
+    // the pixels that still need samples, or None if the pass should be skipped. if sampling all of
+    // them would go over the budget, only the noisiest are sampled, so that it is met exactly
+    fn active_pixels(&self, film: &HdrCanvas, adaptive: &AdaptiveSampling) -> Option<Vec<bool>> {
+        let budget =
+            film.width() as u64 * film.height() as u64 * self.camera.samples_per_pixel() as u64;
+        let total = film.total_samples();
+        if total >= budget {
+            return None;
+        }
+        // how much each pixel needs another sample, if at all. pixels below min_samples come first
+        let mut priorities = Vec::with_capacity((film.width() * film.height()) as usize);
+        for j in 0..film.height() {
+            for i in 0..film.width() {
+                let samples = film.samples(i, j);
+                let error = film.relative_error(i, j);
+                priorities.push(if samples >= adaptive.max_samples {
+                    None
+                } else if samples < adaptive.min_samples {
+                    Some(f64::INFINITY)
+                } else {
+                    (error > adaptive.threshold).then_some(error)
+                });
             }
-            progress_bar.inc(1);
         }
-        let mut output = output.lock().unwrap();
-        for i in 0..image_size {
-            output[i] += result[i];
+        let mut active: Vec<bool> = priorities.iter().map(Option::is_some).collect();
+        let mut candidates: Vec<usize> = (0..active.len()).filter(|&k| active[k]).collect();
+        let remaining = (budget - total) as usize;
+        if candidates.len() > remaining {
+            candidates.select_nth_unstable_by(remaining, |&a, &b| {
+                priorities[b].unwrap().total_cmp(&priorities[a].unwrap())
+            });
+            for &k in &candidates[remaining..] {
+                active[k] = false;
+            }
         }
+        active.contains(&true).then_some(active)
     }
 
-    pub fn render(self) -> Self {
-        let raytracer = self;
-        let width = raytracer.canvas.width();
-        let height = raytracer.canvas.height();
-        let image_size = (width * height) as usize;
-        let sqrt_spp = raytracer.camera.sqrt_spp();
-        let mut threads = Vec::with_capacity(sqrt_spp as usize);
-        let progress = ProgressBar::new((sqrt_spp * sqrt_spp) as u64);
+    pub fn render(mut self) -> Self {
+        self.integrator.preprocess(&self.world, self.seed);
+        let spp = self.camera.samples_per_pixel();
+        let end = self.adaptive.map_or(spp, |adaptive| adaptive.max_samples);
+        let start = self.next_sample.min(end);
+        // snapshots, checkpoints and convergence checks happen between passes of one sample per pixel
+        let pass_size =
+            if self.progressive.is_some() || self.checkpoint.is_some() || self.adaptive.is_some() {
+                1
+            } else {
+                (end - start).max(1)
+            };
+        let passes: Vec<Range<u32>> = (start..end)
+            .step_by(pass_size as usize)
+            .map(|sample| sample..(sample + pass_size).min(end))
+            .collect();
+        let progress = ProgressBar::new(self.tiles().len() as u64 * passes.len() as u64);
         progress.set_style(
             indicatif::ProgressStyle::default_bar()
                 .template("{elapsed_precise} {bar:100.cyan/blue} {pos}/{len}"),
         );
-        let progress = Arc::new(progress);
-        let raytracer = Arc::new(raytracer);
-        let output = Arc::new(Mutex::new(vec![Color::BLACK; image_size]));
-        for si in 0..sqrt_spp {
-            let progress_copy = progress.clone();
-            let raytracer_copy = raytracer.clone();
-            let output_copy = output.clone();
-            threads.push(thread::spawn(move || {
-                Self::render_task(progress_copy, raytracer_copy, output_copy, si, sqrt_spp);
-            }));
+        let film = Mutex::new(std::mem::replace(&mut self.film, HdrCanvas::empty(0, 0)));
+        let mut last_snapshot = Instant::now();
+        let mut last_checkpoint = Instant::now();
+        for (pass, samples) in passes.into_iter().enumerate() {
+            let active = match &self.adaptive {
+                None => None,
+                Some(adaptive) => match self.active_pixels(&film.lock().unwrap(), adaptive) {
+                    None => break,
+                    active => active,
+                },
+            };
+            self.render_pass(&film, samples.clone(), active.as_deref(), &progress);
+            self.next_sample = samples.end;
+            let passes_done = pass as u32 + 1;
+            if let Some((path, interval)) = &self.progressive {
+                if interval.due(passes_done, &mut last_snapshot) {
+                    self.snapshot(&film.lock().unwrap(), path);
+                }
+            }
+            if let Some((path, interval)) = &self.checkpoint {
+                if interval.due(passes_done, &mut last_checkpoint) {
+                    self.save_checkpoint(&film.lock().unwrap(), path);
+                }
+            }
         }
-        // wait for all threads to finish
-        threads
-            .into_iter()
-            .for_each(|thread| thread.join().unwrap());
-        // unwrap the Arcs
-        let mut raytracer = Arc::into_inner(raytracer).unwrap();
-        let output = Arc::into_inner(output).unwrap().into_inner().unwrap();
-        let progress = Arc::into_inner(progress).unwrap();
         progress.finish();
-        // notice that the color should be darkened as it is accumulated from multiple samples
-        let lighten_factor = 1.0 / (sqrt_spp * sqrt_spp) as f64;
-        for i in 0..width {
-            for j in 0..height {
-                raytracer
-                    .canvas
-                    .write(i, j, output[(i * height + j) as usize] * lighten_factor);
-            }
+        self.film = film.into_inner().unwrap();
+        if self.adaptive.is_some() {
+            println!(
+                "Average samples per pixel: {:.1}",
+                self.film.total_samples() as f64
+                    / (self.canvas.width() * self.canvas.height()) as f64
+            );
+        }
+        if let Some((path, _)) = &self.checkpoint {
+            self.save_checkpoint(&self.film, path);
         }
-        raytracer
+        // the film holds the average radiance, which is tone mapped when written to the canvas
+        self.canvas = self.film.to_canvas(&self.tone_map);
+        self
     }
 
+    // exr, pfm and hdr files keep the full dynamic range. other formats are tone mapped
     pub fn save(self, path: &str) -> Self {
-        self.canvas.save(path);
+        if HdrCanvas::is_hdr_path(path) {
+            self.film.save(path);
+        } else {
+            self.canvas.save(path);
+        }
         self
     }
+
+    pub fn film(&self) -> &HdrCanvas {
+        &self.film
+    }
+}
+
+// looking down at the floor of lit_box
+#[cfg(test)]
+fn test_tracer(width: u32, height: u32, samples_per_pixel: u32) -> RayTracer {
+    use crate::camera::{ImageParam, LensParam, PerspectiveParam};
+    use crate::hittable::lit_box;
+    use crate::integrator::PathTracer;
+    use crate::vec3::Vec3;
+
+    let camera = Camera::new(
+        PerspectiveParam {
+            look_from: Vec3::new(0.0, 1.0, 3.0),
+            look_at: Vec3::new(0.0, 0.5, 0.0),
+            view_up: Vec3::new(0.0, 1.0, 0.0),
+        },
+        LensParam {
+            fov: 60.0,
+            defocus_angle: 0.0,
+            focus_dist: 1.0,
+        },
+        ImageParam {
+            image_width: width,
+            image_height: height,
+            sample_per_pixel: samples_per_pixel,
+        },
+    );
+    RayTracer::new(
+        camera,
+        Canvas::empty(width, height),
+        lit_box(true).build(),
+        PathTracer::new(4),
+    )
+}
+
+#[test]
+fn test_seed() {
+    let render = |seed: u64, threads: u32| {
+        test_tracer(40, 40, 2)
+            .set_threads(threads)
+            .set_seed(seed)
+            .render()
+    };
+    let pixels = |tracer: &RayTracer| {
+        let film = tracer.film();
+        (0..film.height())
+            .flat_map(|j| (0..film.width()).map(move |i| film.read(i, j)))
+            .map(|color| (color.r, color.g, color.b))
+            .collect::<Vec<_>>()
+    };
+    // the seed alone decides the image, however the tiles are shared out
+    let image = pixels(&render(1, 1));
+    assert_eq!(image, pixels(&render(1, 4)));
+    assert_ne!(image, pixels(&render(2, 1)));
+}
+
+#[test]
+fn test_adaptive() {
+    let adaptive = AdaptiveSampling {
+        threshold: 0.01,
+        min_samples: 16,
+        max_samples: 64,
+    };
+    let tracer = test_tracer(2, 1, 32);
+    // a constant pixel stops at min_samples, a noisy one goes on
+    let mut film = HdrCanvas::empty(2, 1);
+    for k in 0..16 {
+        film.add_sample(0, 0, Color::WHITE);
+        film.add_sample(1, 0, Color::WHITE * (k % 2) as f64);
+    }
+    let active = tracer.active_pixels(&film, &adaptive);
+    assert_eq!(active, Some(vec![false, true]));
+    // pixels below min_samples come first when the budget runs out
+    let tracer = test_tracer(2, 1, 16);
+    let mut film = HdrCanvas::empty(2, 1);
+    for k in 0..31 {
+        film.add_sample(k % 2, 0, Color::WHITE * (k % 3) as f64);
+    }
+    let active = tracer.active_pixels(&film, &adaptive);
+    assert_eq!(active, Some(vec![false, true]));
+    // the budget is met exactly, even if no pixel converges
+    let adaptive = AdaptiveSampling {
+        threshold: 0.0,
+        min_samples: 2,
+        max_samples: 16,
+    };
+    let tracer = test_tracer(5, 3, 4).set_threads(2).set_adaptive(adaptive);
+    assert_eq!(tracer.render().film().total_samples(), 5 * 3 * 4);
 }
@@ -0,0 +1,434 @@
+use std::str::FromStr;
+
+// source of all the random numbers of a render. the values of a sample only depend on
+// the seed, the pixel and the sample index, so renders are reproducible on any number of threads
+pub trait Sampler {
+    // restart the sequence for the given sample of a pixel
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32);
+    // restart the sequence for the given sample of a stream that belongs to no pixel, e.g. the
+    // light paths shot before rendering. streams and pixels never share values
+    fn start_stream_sample(&mut self, stream: u32, index: u32);
+    // the next value in [0, 1)
+    fn get_1d(&mut self) -> f64;
+    // the next two values in [0, 1)
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// uniform random values without any stratification
+#[derive(Clone, Debug)]
+pub struct IndependentSampler {
+    seed: u64,
+    state: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            state: mix(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state = hash(&[self.seed, 0, pixel.0 as u64, pixel.1 as u64, index as u64]);
+    }
+
+    fn start_stream_sample(&mut self, stream: u32, index: u32) {
+        self.state = hash(&[self.seed, stream as u64 + 1, 0, 0, index as u64]);
+    }
+
+    // splitmix64
+    fn get_1d(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        to_unit(mix(self.state))
+    }
+}
+
+// jittered samples in n strata per dimension, where n is the number of samples per pixel.
+// the strata are shuffled independently for each pixel and dimension (latin hypercube).
+// pairs of dimensions are also stratified on a grid of about sqrt(n) * sqrt(n) cells
+#[derive(Clone, Debug)]
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    // 0 for pixels, one more than the stream otherwise
+    stream: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> Self {
+        Self {
+            seed,
+            samples_per_pixel: samples_per_pixel.max(1),
+            stream: 0,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.stream = 0;
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn start_stream_sample(&mut self, stream: u32, index: u32) {
+        self.start_pixel_sample((0, 0), index);
+        self.stream = stream as u64 + 1;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let (x, y) = self.pixel;
+        let n = self.samples_per_pixel;
+        let key = hash(&[self.seed, self.stream, x as u64, y as u64, self.dimension]);
+        self.dimension += 1;
+        // samples beyond n (e.g. from adaptive sampling) reuse the strata with new jitter
+        let stratum = permute(self.index % n, n, key as u32);
+        let jitter = to_unit(hash(&[key, self.index as u64]));
+        (stratum as f64 + jitter) / n as f64
+    }
+
+    // correlated multi-jittered sampling (Kensler 2013) for any n
+    fn get_2d(&mut self) -> (f64, f64) {
+        let (x, y) = self.pixel;
+        let n = self.samples_per_pixel;
+        let key = hash(&[self.seed, self.stream, x as u64, y as u64, self.dimension]);
+        self.dimension += 1;
+        let columns = (n as f64).sqrt().ceil() as u32;
+        let rows = n.div_ceil(columns);
+        let stratum = permute(self.index % n, n, key as u32);
+        let column = permute(stratum % columns, columns, (key >> 32) as u32);
+        let row = permute(stratum / columns, rows, mix(key) as u32);
+        let jitter = hash(&[key, self.index as u64]);
+        let (jitter_x, jitter_y) = (to_unit(jitter), to_unit(mix(jitter)));
+        (
+            (column as f64 + (row as f64 + jitter_x) / rows as f64) / columns as f64,
+            (stratum as f64 + jitter_y) / n as f64,
+        )
+    }
+}
+
+// the Halton sequence with a prime base per dimension and Owen scrambled digits per pixel.
+// dimensions beyond the prime table fall back to independent values
+#[derive(Clone, Debug)]
+pub struct HaltonSampler {
+    seed: u64,
+    // as in StratifiedSampler
+    stream: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: usize,
+}
+
+impl HaltonSampler {
+    const PRIMES: [u64; 32] = [
+        2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89,
+        97, 101, 103, 107, 109, 113, 127, 131,
+    ];
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            stream: 0,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    // mirror the digits of index about the radix point, permuting each digit depending on the digits
+    // before it. all the digits within f64 precision are scrambled, including the leading zeros
+    fn scrambled_radical_inverse(base: u64, mut index: u64, key: u64) -> f64 {
+        let digits = (53.0 / (base as f64).log2()) as u32;
+        let mut reversed = 0;
+        for _ in 0..digits {
+            let digit = permute(
+                (index % base) as u32,
+                base as u32,
+                mix(key ^ reversed) as u32,
+            );
+            reversed = reversed * base + digit as u64;
+            index /= base;
+        }
+        reversed as f64 / (base as f64).powi(digits as i32)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.stream = 0;
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn start_stream_sample(&mut self, stream: u32, index: u32) {
+        self.start_pixel_sample((0, 0), index);
+        self.stream = stream as u64 + 1;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let (x, y) = self.pixel;
+        let key = hash(&[
+            self.seed,
+            self.stream,
+            x as u64,
+            y as u64,
+            self.dimension as u64,
+        ]);
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match Self::PRIMES.get(dimension) {
+            Some(&base) => Self::scrambled_radical_inverse(base, self.index as u64, key),
+            None => to_unit(hash(&[key, self.index as u64])),
+        }
+    }
+}
+
+// the first two dimensions of the Sobol sequence with hash-based Owen scrambling (Burley 2020).
+// every call draws from its own shuffled and scrambled copy, so all dimensions are well stratified
+// and no two dimensions are correlated. best with a power of two samples per pixel
+#[derive(Clone, Debug)]
+pub struct SobolSampler {
+    seed: u64,
+    // as in StratifiedSampler
+    stream: u64,
+    pixel: (u32, u32),
+    index: u32,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            stream: 0,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    // the index shuffled for this pixel and dimension, and the seeds for scrambling
+    fn next_index(&mut self) -> (u32, u64) {
+        let (x, y) = self.pixel;
+        let key = hash(&[self.seed, self.stream, x as u64, y as u64, self.dimension]);
+        self.dimension += 1;
+        (nested_uniform_scramble(self.index, key as u32), mix(key))
+    }
+
+    fn sobol_0(index: u32) -> u32 {
+        index.reverse_bits()
+    }
+
+    fn sobol_1(mut index: u32) -> u32 {
+        let mut result = 0;
+        let mut direction = 1 << 31;
+        while index != 0 {
+            if index & 1 != 0 {
+                result ^= direction;
+            }
+            index >>= 1;
+            direction ^= direction >> 1;
+        }
+        result
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.stream = 0;
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn start_stream_sample(&mut self, stream: u32, index: u32) {
+        self.start_pixel_sample((0, 0), index);
+        self.stream = stream as u64 + 1;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let (index, key) = self.next_index();
+        u32_to_unit(nested_uniform_scramble(Self::sobol_0(index), key as u32))
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let (index, key) = self.next_index();
+        (
+            u32_to_unit(nested_uniform_scramble(Self::sobol_0(index), key as u32)),
+            u32_to_unit(nested_uniform_scramble(
+                Self::sobol_1(index),
+                (key >> 32) as u32,
+            )),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    #[default]
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn create(self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler \"{}\"", s)),
+        }
+    }
+}
+
+// the finalizer of splitmix64. a bijection that scatters nearby inputs
+pub fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0x2545f4914f6cdd1d, |acc, &value| mix(acc ^ mix(value)))
+}
+
+fn u32_to_unit(x: u32) -> f64 {
+    x as f64 * (1.0 / (1u64 << 32) as f64)
+}
+
+// Laine-Karras style hash with Burley's constants. each bit only depends on the bits below it
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x
+}
+
+// Owen scrambling of the bits from the highest one, which keeps the stratification of the sequence
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// a random permutation of [0, n) indexed by i (Kensler 2013)
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut mask = n - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        // cycle walking until the value is in range
+        if i < n {
+            return (i + seed) % n;
+        }
+    }
+}
+
+// the top 53 bits as a float in [0, 1)
+pub fn to_unit(x: u64) -> f64 {
+    (x >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+#[test]
+fn test_independent_sampler() {
+    let mut a = IndependentSampler::new(1);
+    let mut b = IndependentSampler::new(1);
+    a.start_pixel_sample((3, 4), 5);
+    let values: Vec<f64> = (0..8).map(|_| a.get_1d()).collect();
+    assert!(values.iter().all(|v| (0.0..1.0).contains(v)));
+    // another pixel in between doesn't change the sequence
+    b.start_pixel_sample((4, 3), 5);
+    b.get_2d();
+    b.start_pixel_sample((3, 4), 5);
+    assert_eq!(values, (0..8).map(|_| b.get_1d()).collect::<Vec<_>>());
+    let mut c = IndependentSampler::new(2);
+    c.start_pixel_sample((3, 4), 5);
+    assert_ne!(values[0], c.get_1d());
+    // streams don't repeat the values of pixels
+    for kind in [SamplerKind::Independent, SamplerKind::Sobol] {
+        let mut sampler = kind.create(1, 16);
+        sampler.start_pixel_sample((0, 0), 5);
+        let pixel = sampler.get_1d();
+        sampler.start_stream_sample(0, 5);
+        assert_ne!(pixel, sampler.get_1d());
+    }
+}
+
+#[test]
+fn test_stratification() {
+    // the first dimension of 16 samples hits each of 16 strata once
+    for kind in [
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ] {
+        let mut sampler = kind.create(3, 16);
+        for pixel in [(0, 0), (5, 9)] {
+            let mut strata = [0; 16];
+            for index in 0..16 {
+                sampler.start_pixel_sample(pixel, index);
+                let value = sampler.get_1d();
+                assert!((0.0..1.0).contains(&value));
+                strata[(value * 16.0) as usize] += 1;
+            }
+            assert_eq!(strata, [1; 16], "{:?}", kind);
+        }
+    }
+    for n in [1, 5, 16, 100] {
+        let mut seen: Vec<u32> = (0..n).map(|i| permute(i, n, 12345)).collect();
+        seen.sort();
+        assert_eq!(seen, (0..n).collect::<Vec<_>>());
+    }
+}
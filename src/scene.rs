@@ -0,0 +1,640 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::bvh::{ShapeList, ShapeTree, SplitStrategy};
+use crate::camera::{Camera, ImageParam, LensParam, PerspectiveParam};
+use crate::color::Color;
+use crate::hittable::WorldBuilder;
+use crate::material::{
+    Conductor, Dielectric, Emissive, Isotropic, Lambertian, Material, Metal, RoughDielectric,
+    Translucent,
+};
+use crate::mesh::{Mesh, Triangle};
+use crate::noise::Noise;
+use crate::sampler::{hash, IndependentSampler};
+use crate::shape::{
+    create_cube, ConstantMedium, Instance, Moving, Quad, Shape, Sphere, Transformed,
+};
+use crate::texture::{Atlas, CheckerTexture, ImageTexture, NoiseTexture, SolidColor, Texture};
+use crate::transform::Transform;
+use crate::vec3::Vec3;
+
+// an error while loading a scene file. entry points at the offending part, e.g. "objects[3].material"
+#[derive(Debug)]
+pub struct SceneError {
+    pub entry: String,
+    pub message: String,
+}
+
+impl SceneError {
+    fn new(entry: &str, message: impl Display) -> Self {
+        Self {
+            entry: entry.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for SceneError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.entry.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "in `{}`: {}", self.entry, self.message)
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+type Triple = [f64; 3];
+
+fn vec3(v: Triple) -> Vec3 {
+    Vec3::new(v[0], v[1], v[2])
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: Value,
+    background: Option<Value>,
+    max_depth: Option<u32>,
+    bvh: Option<BvhDesc>,
+    #[serde(default)]
+    objects: Vec<Value>,
+    #[serde(default)]
+    lights: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    perspective: PerspectiveDesc,
+    lens: LensDesc,
+    image: ImageDesc,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PerspectiveDesc {
+    look_from: Triple,
+    look_at: Triple,
+    view_up: Triple,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LensDesc {
+    fov: f64,
+    #[serde(default)]
+    defocus_angle: f64,
+    focus_dist: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImageDesc {
+    image_width: u32,
+    image_height: u32,
+    sample_per_pixel: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BvhDesc {
+    Median,
+    Sah,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    shape: Value,
+    material: Value,
+    atlas: Option<Value>,
+}
+
+// either [r, g, b] in linear space or a "#RRGGBB" hex string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorDesc {
+    Linear(Triple),
+    Hex(String),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+    Solid {
+        color: ColorDesc,
+    },
+    Image {
+        path: String,
+    },
+    Checker {
+        even: ColorDesc,
+        odd: ColorDesc,
+        scale: f64,
+    },
+    Noise {
+        scale: f64,
+        #[serde(default)]
+        seed: u64,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AtlasDesc {
+    transparency: Option<TextureDesc>,
+    attenuation: Option<TextureDesc>,
+    emission: Option<TextureDesc>,
+    normal: Option<TextureDesc>,
+    roughness: Option<TextureDesc>,
+    absorption: Option<TextureDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian,
+    Metal {
+        fuzz: f64,
+    },
+    Conductor {
+        metal: MetalDesc,
+        #[serde(default)]
+        roughness: f64,
+    },
+    // absorption is per unit of distance inside. clear if left out, unless the atlas has an
+    // absorption texture
+    Dielectric {
+        refraction_index: f64,
+        absorption: Option<ColorDesc>,
+    },
+    RoughDielectric {
+        refraction_index: f64,
+        roughness: f64,
+        absorption: Option<ColorDesc>,
+    },
+    Isotropic {
+        glow: f64,
+    },
+    Translucent {
+        refraction_index: f64,
+    },
+    Emissive {
+        ratio: f64,
+    },
+}
+
+// a preset name, e.g. "gold", or the complex index of refraction per channel
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MetalDesc {
+    Preset(String),
+    Measured { eta: Triple, k: Triple },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ShapeDesc {
+    Sphere {
+        center: Triple,
+        radius: f64,
+    },
+    Quad {
+        q: Triple,
+        u: Triple,
+        v: Triple,
+    },
+    Cube {
+        a: Triple,
+        b: Triple,
+    },
+    // a named sub-mesh of an obj file
+    Mesh {
+        path: String,
+        name: String,
+    },
+    Group {
+        shapes: Vec<ShapeDesc>,
+    },
+    Medium {
+        density: f64,
+        boundary: Box<ShapeDesc>,
+    },
+    Moving {
+        direction: Triple,
+        shape: Box<ShapeDesc>,
+    },
+    // the operations are applied in the listed order
+    Transformed {
+        transform: Vec<TransformDesc>,
+        shape: Box<ShapeDesc>,
+    },
+    // copies of one shape, one per transform. the shape is built once and a tree is built over the
+    // copies, so a mesh placed thousands of times keeps a single tree of triangles
+    Instances {
+        transforms: Vec<Vec<TransformDesc>>,
+        shape: Box<ShapeDesc>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDesc {
+    Translate(Triple),
+    Scale(Triple),
+    RotateX(f64),
+    RotateY(f64),
+    RotateZ(f64),
+}
+
+pub struct Scene {
+    pub world: WorldBuilder,
+    // problems that don't stop the scene from loading, e.g. emitters that are never sampled
+    pub warnings: Vec<SceneError>,
+    pub perspective: PerspectiveParam,
+    pub lens: LensParam,
+    pub image: ImageParam,
+    pub max_depth: u32,
+    // a hash of the scene file, so that checkpoints of other scenes aren't resumed
+    pub id: u64,
+}
+
+impl Scene {
+    pub const DEFAULT_MAX_DEPTH: u32 = 50;
+
+    pub fn load(path: &str) -> Result<Self, SceneError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| SceneError::new("", format!("cannot read \"{}\": {}", path, e)))?;
+        let id = hash(&text.bytes().map(u64::from).collect::<Vec<_>>());
+        let desc: SceneDesc = serde_json::from_str(&text)
+            .map_err(|e| SceneError::new("", format!("invalid scene file \"{}\": {}", path, e)))?;
+        let base = Path::new(path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let strategy = match desc.bvh {
+            Some(BvhDesc::Median) => SplitStrategy::Median,
+            Some(BvhDesc::Sah) | None => SplitStrategy::Sah,
+        };
+        let mut loader = Loader {
+            base,
+            strategy,
+            meshes: HashMap::default(),
+        };
+
+        let camera: CameraDesc = parse(desc.camera, "camera")?;
+        let mut world = WorldBuilder::default();
+        world.set_split_strategy(strategy);
+        // the first emissive object, to warn about if no light is given
+        let mut emitter = None;
+        if let Some(background) = desc.background {
+            world.set_background(color(parse(background, "background")?, "background")?);
+        }
+        for (i, object) in desc.objects.into_iter().enumerate() {
+            let entry = format!("objects[{}]", i);
+            let object: ObjectDesc = parse(object, &entry)?;
+            let shape_entry = format!("{}.shape", entry);
+            let shape = loader.shape(parse(object.shape, &shape_entry)?, &shape_entry)?;
+            let material_entry = format!("{}.material", entry);
+            let material: MaterialDesc = parse(object.material, &material_entry)?;
+            if matches!(material, MaterialDesc::Emissive { .. }) && emitter.is_none() {
+                emitter = Some(entry.clone());
+            }
+            let material = self::material(material, &material_entry)?;
+            let atlas = match object.atlas {
+                Some(atlas) => {
+                    let atlas_entry = format!("{}.atlas", entry);
+                    loader.atlas(parse(atlas, &atlas_entry)?, &atlas_entry)?
+                }
+                None => Atlas::default(),
+            };
+            world.add_object(shape, material, atlas);
+        }
+        let mut warnings = Vec::new();
+        if let (Some(entry), true) = (emitter, desc.lights.is_empty()) {
+            warnings.push(SceneError::new(
+                &entry,
+                "emissive objects are only hit by chance since no lights are given",
+            ));
+        }
+        for (i, light) in desc.lights.into_iter().enumerate() {
+            let entry = format!("lights[{}]", i);
+            match parse(light, &entry)? {
+                ShapeDesc::Sphere { center, radius } => {
+                    world.add_light(Sphere::new(vec3(center), radius))
+                }
+                ShapeDesc::Quad { q, u, v } => {
+                    world.add_light(Quad::new(vec3(q), vec3(u), vec3(v)))
+                }
+                _ => {
+                    return Err(SceneError::new(
+                        &entry,
+                        "only spheres and quads can be sampled as lights",
+                    ))
+                }
+            }
+        }
+
+        Ok(Self {
+            world,
+            warnings,
+            perspective: PerspectiveParam {
+                look_from: vec3(camera.perspective.look_from),
+                look_at: vec3(camera.perspective.look_at),
+                view_up: vec3(camera.perspective.view_up),
+            },
+            lens: LensParam {
+                fov: camera.lens.fov,
+                defocus_angle: camera.lens.defocus_angle,
+                focus_dist: camera.lens.focus_dist,
+            },
+            image: ImageParam {
+                image_width: camera.image.image_width,
+                image_height: camera.image.image_height,
+                sample_per_pixel: camera.image.sample_per_pixel,
+            },
+            max_depth: desc.max_depth.unwrap_or(Self::DEFAULT_MAX_DEPTH),
+            id,
+        })
+    }
+
+    pub fn camera(&self) -> Camera {
+        Camera::new(self.perspective, self.lens, self.image)
+    }
+}
+
+fn parse<T: DeserializeOwned>(value: Value, entry: &str) -> Result<T, SceneError> {
+    serde_json::from_value(value).map_err(|e| SceneError::new(entry, e))
+}
+
+fn color(desc: ColorDesc, entry: &str) -> Result<Color, SceneError> {
+    match desc {
+        ColorDesc::Linear(c) => Ok(Color::new(c[0], c[1], c[2])),
+        ColorDesc::Hex(hex) => {
+            let digits = hex.strip_prefix('#').unwrap_or(&hex);
+            let value = u32::from_str_radix(digits, 16)
+                .ok()
+                .filter(|_| digits.len() == 6)
+                .ok_or_else(|| SceneError::new(entry, format!("invalid hex color \"{}\"", hex)))?;
+            Ok(Color::new_u8(
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ))
+        }
+    }
+}
+
+fn transform(desc: Vec<TransformDesc>) -> Transform {
+    desc.into_iter().fold(Transform::default(), |acc, op| {
+        let op = match op {
+            TransformDesc::Translate(v) => Transform::translate(vec3(v)),
+            TransformDesc::Scale(v) => Transform::scale(vec3(v)),
+            TransformDesc::RotateX(angle) => Transform::rotate_x(angle),
+            TransformDesc::RotateY(angle) => Transform::rotate_y(angle),
+            TransformDesc::RotateZ(angle) => Transform::rotate_z(angle),
+        };
+        op * acc
+    })
+}
+
+fn material(desc: MaterialDesc, entry: &str) -> Result<Box<dyn Material>, SceneError> {
+    Ok(match desc {
+        MaterialDesc::Lambertian => Box::new(Lambertian),
+        MaterialDesc::Metal { fuzz } => Box::new(Metal::new(fuzz)),
+        MaterialDesc::Dielectric {
+            refraction_index,
+            absorption,
+        } => {
+            let mut material = Dielectric::new(refraction_index);
+            if let Some(absorption) = absorption {
+                material = material.set_absorption(color(absorption, entry)?);
+            }
+            Box::new(material)
+        }
+        MaterialDesc::RoughDielectric {
+            refraction_index,
+            roughness,
+            absorption,
+        } => {
+            let mut material = RoughDielectric::new(refraction_index, roughness);
+            if let Some(absorption) = absorption {
+                material = material.set_absorption(color(absorption, entry)?);
+            }
+            Box::new(material)
+        }
+        MaterialDesc::Isotropic { glow } => Box::new(Isotropic::new(glow)),
+        MaterialDesc::Translucent { refraction_index } => {
+            Box::new(Translucent::new(refraction_index))
+        }
+        MaterialDesc::Conductor { metal, roughness } => match metal {
+            MetalDesc::Preset(name) => Box::new(
+                Conductor::preset(&name, roughness)
+                    .ok_or_else(|| SceneError::new(entry, format!("unknown metal \"{}\"", name)))?,
+            ),
+            MetalDesc::Measured { eta, k } => Box::new(Conductor::new(
+                Color::new(eta[0], eta[1], eta[2]),
+                Color::new(k[0], k[1], k[2]),
+                roughness,
+            )),
+        },
+        MaterialDesc::Emissive { ratio } => Box::new(Emissive::new(ratio)),
+    })
+}
+
+struct Loader {
+    // asset paths are relative to the scene file
+    base: PathBuf,
+    strategy: SplitStrategy,
+    // a sub-mesh may be placed several times, so its tree is kept by the mesh and shared
+    meshes: HashMap<String, Mesh>,
+}
+
+impl Loader {
+    fn resolve(&self, path: &str) -> String {
+        self.base.join(path).to_string_lossy().into_owned()
+    }
+
+    fn shape(&mut self, desc: ShapeDesc, entry: &str) -> Result<Box<dyn Shape>, SceneError> {
+        Ok(match desc {
+            ShapeDesc::Sphere { center, radius } => Box::new(Sphere::new(vec3(center), radius)),
+            ShapeDesc::Quad { q, u, v } => Box::new(Quad::new(vec3(q), vec3(u), vec3(v))),
+            ShapeDesc::Cube { a, b } => Box::new(create_cube(vec3(a), vec3(b))),
+            ShapeDesc::Mesh { path, name } => Box::new(self.mesh_shape(&path, &name, entry)?),
+            ShapeDesc::Group { shapes } => {
+                let mut list = ShapeList::default();
+                for (i, shape) in shapes.into_iter().enumerate() {
+                    list.push(self.shape(shape, &format!("{}.shapes[{}]", entry, i))?);
+                }
+                Box::new(list)
+            }
+            ShapeDesc::Medium { density, boundary } => {
+                let boundary = self.shape(*boundary, &format!("{}.boundary", entry))?;
+                Box::new(ConstantMedium::new(density, boundary))
+            }
+            ShapeDesc::Moving { direction, shape } => {
+                let shape = self.shape(*shape, &format!("{}.shape", entry))?;
+                Box::new(Moving::new(vec3(direction), shape))
+            }
+            ShapeDesc::Transformed { transform, shape } => {
+                let shape = self.shape(*shape, &format!("{}.shape", entry))?;
+                Box::new(Transformed::new(self::transform(transform), shape))
+            }
+            ShapeDesc::Instances { transforms, shape } => {
+                let shape = self.shape(*shape, &format!("{}.shape", entry))?;
+                let shape: Arc<dyn Shape> = Arc::from(shape);
+                let instances: Vec<Instance<dyn Shape>> = transforms
+                    .into_iter()
+                    .map(|transform| Transformed::new(self::transform(transform), shape.clone()))
+                    .collect();
+                Box::new(ShapeTree::with_strategy(instances, self.strategy))
+            }
+        })
+    }
+
+    fn mesh_shape(
+        &mut self,
+        path: &str,
+        name: &str,
+        entry: &str,
+    ) -> Result<Arc<ShapeTree<Triangle>>, SceneError> {
+        let path = self.resolve(path);
+        if !self.meshes.contains_key(&path) {
+            let mesh = Mesh::open(&path).map_err(|e| {
+                SceneError::new(entry, format!("cannot load mesh \"{}\": {}", path, e))
+            })?;
+            self.meshes.insert(path.clone(), mesh);
+        }
+        self.meshes
+            .get_mut(&path)
+            .unwrap()
+            .blas(name, self.strategy)
+            .ok_or_else(|| {
+                SceneError::new(
+                    entry,
+                    format!("no shape named \"{}\" in \"{}\"", name, path),
+                )
+            })
+    }
+
+    fn texture(&self, desc: TextureDesc, entry: &str) -> Result<Box<dyn Texture>, SceneError> {
+        Ok(match desc {
+            TextureDesc::Solid { color } => Box::new(SolidColor::new(self::color(color, entry)?)),
+            TextureDesc::Image { path } => {
+                let path = self.resolve(&path);
+                Box::new(ImageTexture::open(&path).map_err(|e| {
+                    SceneError::new(entry, format!("cannot open image \"{}\": {}", path, e))
+                })?)
+            }
+            TextureDesc::Checker { even, odd, scale } => Box::new(CheckerTexture::new(
+                self::color(even, entry)?,
+                self::color(odd, entry)?,
+                scale,
+            )),
+            TextureDesc::Noise { scale, seed } => Box::new(NoiseTexture::new(
+                Noise::new(&mut IndependentSampler::new(seed)),
+                scale,
+            )),
+        })
+    }
+
+    fn atlas(&self, desc: AtlasDesc, entry: &str) -> Result<Atlas, SceneError> {
+        let texture = |desc: Option<TextureDesc>, name: &str| {
+            desc.map(|desc| self.texture(desc, &format!("{}.{}", entry, name)))
+                .transpose()
+        };
+        Ok(Atlas {
+            transparency: texture(desc.transparency, "transparency")?,
+            attenuation: texture(desc.attenuation, "attenuation")?,
+            emission: texture(desc.emission, "emission")?,
+            normal: texture(desc.normal, "normal")?,
+            roughness: texture(desc.roughness, "roughness")?,
+            absorption: texture(desc.absorption, "absorption")?,
+        })
+    }
+}
+
+#[test]
+fn test_scene() {
+    // load a scene with the given objects and lights, and return the error if any
+    let load = |objects: &str, lights: &str| {
+        let text = format!(
+            r#"{{
+                "camera": {{
+                    "perspective": {{"look_from": [0, 0, 1], "look_at": [0, 0, 0], "view_up": [0, 1, 0]}},
+                    "lens": {{"fov": 40, "focus_dist": 1}},
+                    "image": {{"image_width": 4, "image_height": 2, "sample_per_pixel": 8}}
+                }},
+                "objects": [{}],
+                "lights": [{}]
+            }}"#,
+            objects, lights
+        );
+        let path = std::env::temp_dir().join("raytracer_test_scene.json");
+        let path = path.to_str().unwrap();
+        fs::write(path, text).unwrap();
+        let scene = Scene::load(path);
+        fs::remove_file(path).unwrap();
+        scene
+    };
+    let error = |objects: &str| load(objects, "").err().unwrap();
+    let sphere = r#"{"type": "sphere", "center": [0, 0, 0], "radius": 1}"#;
+    let object = |shape: &str, material: &str| {
+        format!(r#"{{"shape": {}, "material": {}}}"#, shape, material)
+    };
+
+    let lambertian = object(sphere, r#"{"type": "lambertian"}"#);
+    let scene = load(&lambertian, "").ok().unwrap();
+    assert_eq!(scene.image.image_width, 4);
+    assert_eq!(scene.image.sample_per_pixel, 8);
+    assert_eq!(scene.max_depth, Scene::DEFAULT_MAX_DEPTH);
+    assert!(scene.warnings.is_empty());
+    // emitters that aren't lights are pointed out
+    let emissive = object(sphere, r#"{"type": "emissive", "ratio": 1}"#);
+    let objects = format!("{}, {}", lambertian, emissive);
+    let warnings = load(&objects, "").ok().unwrap().warnings;
+    assert_eq!(warnings[0].entry, "objects[1]");
+    assert!(load(&objects, sphere).ok().unwrap().warnings.is_empty());
+
+    let e = error(&object(sphere, r#"{"type": "plastic"}"#));
+    assert_eq!(e.entry, "objects[0].material");
+    let e = error(&object(
+        sphere,
+        r#"{"type": "conductor", "metal": "unobtainium"}"#,
+    ));
+    assert_eq!(e.entry, "objects[0].material");
+    assert!(e.message.contains("unknown metal"));
+    let e = error(&format!(
+        r##"{{"shape": {}, "material": {{"type": "lambertian"}},
+            "atlas": {{"attenuation": {{"type": "solid", "color": "#12345"}}}}}}"##,
+        sphere
+    ));
+    assert_eq!(e.entry, "objects[0].atlas.attenuation");
+    assert!(e.message.contains("invalid hex color"));
+    // errors deep inside a shape point at the nested shape
+    let group = format!(
+        r#"{{"type": "group", "shapes": [{}, {{"type": "transformed", "transform": [],
+            "shape": {{"type": "mesh", "path": "missing.obj", "name": "cube"}}}}]}}"#,
+        sphere
+    );
+    let e = error(&object(&group, r#"{"type": "lambertian"}"#));
+    assert_eq!(e.entry, "objects[0].shape.shapes[1].shape");
+}
+
+#[test]
+fn test_default_scene() {
+    // the scene rendered when none is given must load on its own
+    let scene = Scene::load("assets/cornell_box.json").ok().unwrap();
+    assert!(scene.warnings.is_empty());
+}
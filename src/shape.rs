@@ -1,5 +1,6 @@
 use std::f64::consts::PI;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 use crate::aabb::Aabb;
 use crate::bvh::ShapeList;
@@ -8,22 +9,77 @@ use crate::hit_record::HitRecord;
 use crate::interval::Interval;
 use crate::onb::Onb;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::texture::{Atlas, UV};
+use crate::transform::Transform;
 use crate::vec3::Vec3;
 
 pub trait Shape: Sync + Send {
     // hit_record.ray is the original ray. (may contain the former hit info)
     // if hit, set hit info and interval before returning true
     // may use material for decoration. absorb is the default behavior
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool;
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool;
+    // whether anything in the interval is hit, e.g. for shadow rays. may stop at any hit rather than
+    // the closest one, so only the result is meaningful. transparent parts still let the ray through
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.hit(hit_record, atlas, sampler)
+    }
     // return the bounding box for hit testing. only called once for construction
     fn bounding_box(&self) -> Aabb;
 }
 
+impl<T: Shape + ?Sized> Shape for Box<T> {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
+        self.as_ref().hit(hit_record, atlas, sampler)
+    }
+
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.as_ref().occluded(hit_record, atlas, sampler)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.as_ref().bounding_box()
+    }
+}
+
+// share one shape (e.g. a mesh tree) between several placements
+impl<T: Shape + ?Sized> Shape for Arc<T> {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
+        self.as_ref().hit(hit_record, atlas, sampler)
+    }
+
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.as_ref().occluded(hit_record, atlas, sampler)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.as_ref().bounding_box()
+    }
+}
+
 pub trait ShapePDFProvider: Shape + Debug {
     // similar to PDF but we specify the origin
     fn prob(&self, origin: Vec3, direction: Vec3) -> f64;
-    fn generate(&self, origin: Vec3) -> Vec3;
+    fn generate(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3;
+    // a point uniformly distributed over the surface and the outward normal there. used for
+    // starting paths on lights
+    fn sample_area(&self, sampler: &mut dyn Sampler) -> (Vec3, Vec3);
+    fn area(&self) -> f64;
 }
 
 #[derive(Debug)]
@@ -37,6 +93,22 @@ impl Sphere {
         Self { center, radius }
     }
 
+    // the nearest root of the ray in the interval
+    fn intersect(&self, ray: &Ray, interval: Interval) -> Option<f64> {
+        let oc = self.center - ray.origin;
+        let a = ray.direction.length_squared();
+        let h = ray.direction.dot(oc);
+        let c = oc.length_squared() - self.radius * self.radius;
+        let discriminant = h * h - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let sqrt_d = discriminant.sqrt();
+        [(h - sqrt_d) / a, (h + sqrt_d) / a]
+            .into_iter()
+            .find(|&root| interval.surrounds(root))
+    }
+
     fn uv_from_normal(p: Vec3) -> UV {
         let theta = (-p.y).acos();
         let phi = (-p.z).atan2(p.x) + PI;
@@ -45,25 +117,11 @@ impl Sphere {
 }
 
 impl Shape for Sphere {
-    fn hit(&self, hit_record: &mut HitRecord, _atlas: &Atlas) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, _atlas: &Atlas, _sampler: &mut dyn Sampler) -> bool {
         let ray = hit_record.get_ray();
-        let interval = hit_record.get_interval();
-        let oc = self.center - ray.origin;
-        let a = ray.direction.length_squared();
-        let h = ray.direction.dot(oc);
-        let c = oc.length_squared() - self.radius * self.radius;
-        let discriminant = h * h - a * c;
-        if discriminant < 0.0 {
+        let Some(root) = self.intersect(ray, hit_record.get_interval()) else {
             return false;
-        }
-        let sqrt_d = discriminant.sqrt();
-        let mut root = (h - sqrt_d) / a;
-        if !interval.surrounds(root) {
-            root = (h + sqrt_d) / a;
-            if !interval.surrounds(root) {
-                return false;
-            }
-        }
+        };
         let position = ray.at(root);
         let outward_normal = (position - self.center) / self.radius;
         hit_record.set_hit(root, outward_normal, Self::uv_from_normal(outward_normal))
@@ -77,10 +135,10 @@ impl Shape for Sphere {
 
 impl ShapePDFProvider for Sphere {
     fn prob(&self, origin: Vec3, direction: Vec3) -> f64 {
-        let ray = Ray::new(origin, direction);
-        let mut hit_record = HitRecord::new(ray);
-        if !self.hit(&mut hit_record, &Atlas::default()) {
-            // atlas is not applicable
+        if self
+            .intersect(&Ray::new(origin, direction), Interval::POSITIVE)
+            .is_none()
+        {
             return 0.0;
         }
         let cos_theta_max =
@@ -89,11 +147,24 @@ impl ShapePDFProvider for Sphere {
         1.0 / solid_angle
     }
 
-    fn generate(&self, origin: Vec3) -> Vec3 {
+    fn generate(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
         let direction = self.center - origin;
         let distance_squared = direction.length_squared();
         let uvw = Onb::normal(direction);
-        uvw.local(Vec3::random_to_sphere(self.radius, distance_squared))
+        uvw.local(Vec3::random_to_sphere(
+            self.radius,
+            distance_squared,
+            sampler,
+        ))
+    }
+
+    fn sample_area(&self, sampler: &mut dyn Sampler) -> (Vec3, Vec3) {
+        let normal = Vec3::random_unit_vector(sampler);
+        (self.center + normal * self.radius, normal)
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
     }
 }
 
@@ -125,24 +196,29 @@ impl Quad {
             area,
         }
     }
-}
 
-impl Shape for Quad {
-    fn hit(&self, hit_record: &mut HitRecord, _atlas: &Atlas) -> bool {
-        let ray = hit_record.get_ray();
+    // return t and the planar coordinates of the hit in the interval
+    fn intersect(&self, ray: &Ray, interval: Interval) -> Option<(f64, UV)> {
         let denominator = self.normal.dot(ray.direction);
         let t = (self.d - self.normal.dot(ray.origin)) / denominator;
-        if !hit_record.get_interval().contains(t) {
-            return false;
+        if !interval.contains(t) {
+            return None;
         }
         let intersection = ray.at(t);
         let planar_hit_pos = intersection - self.q;
         let alpha = self.w.dot(planar_hit_pos * self.v);
         let beta = self.w.dot(self.u * planar_hit_pos);
-        if Interval::UNIT.contains(alpha) && Interval::UNIT.contains(beta) {
-            return hit_record.set_hit(t, self.normal, UV::new(alpha, beta));
+        (Interval::UNIT.contains(alpha) && Interval::UNIT.contains(beta))
+            .then_some((t, UV::new(alpha, beta)))
+    }
+}
+
+impl Shape for Quad {
+    fn hit(&self, hit_record: &mut HitRecord, _atlas: &Atlas, _sampler: &mut dyn Sampler) -> bool {
+        match self.intersect(hit_record.get_ray(), hit_record.get_interval()) {
+            Some((t, uv)) => hit_record.set_hit(t, self.normal, uv),
+            None => false,
         }
-        false
     }
 
     fn bounding_box(&self) -> Aabb {
@@ -155,20 +231,26 @@ impl Shape for Quad {
 
 impl ShapePDFProvider for Quad {
     fn prob(&self, origin: Vec3, direction: Vec3) -> f64 {
-        let ray = Ray::new(origin, direction);
-        let mut hit_record = HitRecord::new(ray);
-        if !self.hit(&mut hit_record, &Atlas::default()) {
-            // atlas is not applicable
+        let Some((t, _)) = self.intersect(&Ray::new(origin, direction), Interval::POSITIVE) else {
             return 0.0;
-        }
-        let hit = hit_record.get_hit();
-        let distance_squared = hit.t * hit.t * direction.length_squared();
-        let cosine = -direction.dot(hit.normal) / direction.length();
+        };
+        let distance_squared = t * t * direction.length_squared();
+        let cosine = direction.dot(self.normal).abs() / direction.length();
         distance_squared / (cosine * self.area)
     }
 
-    fn generate(&self, origin: Vec3) -> Vec3 {
-        self.q + self.u * rand::random::<f64>() + self.v * rand::random::<f64>() - origin
+    fn generate(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (s, t) = sampler.get_2d();
+        self.q + self.u * s + self.v * t - origin
+    }
+
+    fn sample_area(&self, sampler: &mut dyn Sampler) -> (Vec3, Vec3) {
+        let (s, t) = sampler.get_2d();
+        (self.q + self.u * s + self.v * t, self.normal)
+    }
+
+    fn area(&self) -> f64 {
+        self.area
     }
 }
 
@@ -225,10 +307,10 @@ impl<T: Shape> Moving<T> {
 }
 
 impl<T: Shape> Shape for Moving<T> {
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
         let shift = self.direction * hit_record.get_ray().time;
         hit_record.get_ray_mut().origin -= shift;
-        let hit = self.shape.hit(hit_record, atlas);
+        let hit = self.shape.hit(hit_record, atlas, sampler);
         hit_record.get_ray_mut().origin += shift;
         if hit {
             hit_record.get_hit_mut().position += shift;
@@ -236,12 +318,98 @@ impl<T: Shape> Shape for Moving<T> {
         hit
     }
 
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let shift = self.direction * hit_record.get_ray().time;
+        hit_record.get_ray_mut().origin -= shift;
+        let hit = self.shape.occluded(hit_record, atlas, sampler);
+        hit_record.get_ray_mut().origin += shift;
+        hit
+    }
+
     fn bounding_box(&self) -> Aabb {
         let stationary_aabb = self.shape.bounding_box();
         stationary_aabb.union(stationary_aabb + self.direction)
     }
 }
 
+// a placement of a shared shape, e.g. the tree of a mesh. any number of instances cost one tree
+pub type Instance<T> = Transformed<Arc<T>>;
+
+pub struct Transformed<T: Shape> {
+    to_world: Transform,
+    to_object: Transform,
+    normal_matrix: Transform,
+    shape: T,
+}
+
+impl<T: Shape> Transformed<T> {
+    // transform maps object space to world space
+    pub fn new(transform: Transform, shape: T) -> Self {
+        let to_object = transform.inverse();
+        Self {
+            to_world: transform,
+            to_object,
+            normal_matrix: transform.normal_matrix(),
+            shape,
+        }
+    }
+
+    // brings the hit info back to world space
+    fn hit_to_world(&self, hit_record: &mut HitRecord) {
+        let hit_info = hit_record.get_hit_mut();
+        hit_info.position = self.to_world.pos(hit_info.position);
+        // arbitrary hits (e.g. inside a medium) have no normal
+        if hit_info.normal.length_squared() > 0.0 {
+            hit_info.normal = self.normal_matrix.direction(hit_info.normal).normalize();
+        }
+    }
+}
+
+impl<T: Shape> Shape for Transformed<T> {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
+        // the direction isn't normalized, so t is the same in both spaces
+        let origin = hit_record.get_ray().origin;
+        let direction = hit_record.get_ray().direction;
+        hit_record.get_ray_mut().origin = self.to_object.pos(origin);
+        hit_record.get_ray_mut().direction = self.to_object.direction(direction);
+        let hit = self.shape.hit(hit_record, atlas, sampler);
+        hit_record.get_ray_mut().origin = origin;
+        hit_record.get_ray_mut().direction = direction;
+        if hit {
+            self.hit_to_world(hit_record);
+        }
+        hit
+    }
+
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let origin = hit_record.get_ray().origin;
+        let direction = hit_record.get_ray().direction;
+        hit_record.get_ray_mut().origin = self.to_object.pos(origin);
+        hit_record.get_ray_mut().direction = self.to_object.direction(direction);
+        let hit = self.shape.occluded(hit_record, atlas, sampler);
+        hit_record.get_ray_mut().origin = origin;
+        hit_record.get_ray_mut().direction = direction;
+        if hit {
+            self.hit_to_world(hit_record);
+        }
+        hit
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.to_world.bounding_box(self.shape.bounding_box())
+    }
+}
+
 pub struct ConstantMedium<T: Shape> {
     neg_inv_density: f64,
     boundary: T,
@@ -257,16 +425,16 @@ impl<T: Shape> ConstantMedium<T> {
 }
 
 impl<T: Shape> Shape for ConstantMedium<T> {
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
         let ray = hit_record.get_ray();
         let mut rec = HitRecord::new(ray.clone());
         rec.set_interval(Interval::UNIVERSE);
-        if !self.boundary.hit(&mut rec, atlas) {
+        if !self.boundary.hit(&mut rec, atlas, sampler) {
             return false;
         }
         let t1 = rec.get_hit().t;
         rec.set_interval(Interval::new(t1 + Interval::DELTA, f64::INFINITY));
-        if !self.boundary.hit(&mut rec, atlas) {
+        if !self.boundary.hit(&mut rec, atlas, sampler) {
             return false;
         }
         let t2 = rec.get_hit().t;
@@ -275,7 +443,7 @@ impl<T: Shape> Shape for ConstantMedium<T> {
             return false;
         }
         let ray_length = hit_record.get_ray().direction.length();
-        let hit_distance = self.neg_inv_density * rand::random::<f64>().ln();
+        let hit_distance = self.neg_inv_density * (1.0 - sampler.get_1d()).ln();
         let t = interval.min + hit_distance / ray_length;
         if !interval.surrounds(t) {
             return false;
@@ -306,19 +474,17 @@ impl<T: Shape> Edge<T> {
 }
 
 impl<T: Shape> Shape for Edge<T> {
-    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas) -> bool {
+    fn hit(&self, hit_record: &mut HitRecord, atlas: &Atlas, sampler: &mut dyn Sampler) -> bool {
         let uvw = Onb::normal(hit_record.get_ray().direction);
-        if !self.shape.hit(hit_record, atlas) {
+        if !self.shape.hit(hit_record, atlas, sampler) {
             return false;
         }
         for _ in 0..self.clarity {
+            let offset = uvw.local(Vec3::random_in_unit_disk(sampler) * self.radius);
             if !self.shape.hit(
-                &mut HitRecord::new(
-                    hit_record
-                        .get_ray()
-                        .offset(uvw.local(Vec3::random_in_unit_disk() * self.radius)),
-                ),
+                &mut HitRecord::new(hit_record.get_ray().offset(offset)),
                 atlas,
+                sampler,
             ) {
                 hit_record.get_hit_mut().emission = Color::WHITE; // simply mark as edge.
                 return true;
@@ -327,7 +493,44 @@ impl<T: Shape> Shape for Edge<T> {
         true
     }
 
+    // the edge only decorates the hit
+    fn occluded(
+        &self,
+        hit_record: &mut HitRecord,
+        atlas: &Atlas,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.shape.occluded(hit_record, atlas, sampler)
+    }
+
     fn bounding_box(&self) -> Aabb {
         self.shape.bounding_box()
     }
 }
+
+#[test]
+fn test_transformed() {
+    use crate::sampler::IndependentSampler;
+
+    // a unit sphere stretched along x, then turned to lie along y: semi-axes (1, 2, 1)
+    let center = Vec3::new(1.0, 2.0, 3.0);
+    let transform = Transform::translate(center)
+        * Transform::rotate_z(90.0)
+        * Transform::scale(Vec3::new(2.0, 1.0, 1.0));
+    let ellipsoid = Transformed::new(transform, Sphere::new(Vec3::default(), 1.0));
+    let aabb = ellipsoid.bounding_box();
+    assert!((aabb.min_pos() - Vec3::new(0.0, 0.0, 2.0)).length() < 1e-9);
+    assert!((aabb.max_pos() - Vec3::new(2.0, 4.0, 4.0)).length() < 1e-9);
+    // enters where x^2 + y^2 / 4 = 1 at y = 1 from the center
+    let ray = Ray::new(center + Vec3::new(5.0, 1.0, 0.0), Vec3::new(-1.0, 0.0, 0.0));
+    let mut hit_record = HitRecord::new(ray);
+    let mut sampler = IndependentSampler::new(0);
+    assert!(ellipsoid.hit(&mut hit_record, &Atlas::default(), &mut sampler));
+    let hit = hit_record.get_hit();
+    let x = 3.0_f64.sqrt() / 2.0;
+    assert!((hit.t - (5.0 - x)).abs() < 1e-9);
+    assert!((hit.position - (center + Vec3::new(x, 1.0, 0.0))).length() < 1e-9);
+    // the normal follows the gradient (2x, y / 2, 0), not the stretched object normal
+    let normal = Vec3::new(2.0 * x, 0.5, 0.0).normalize();
+    assert!((hit.normal - normal).length() < 1e-9);
+}
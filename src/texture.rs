@@ -111,6 +111,12 @@ impl ImageTexture {
             image: Canvas::from_path(path),
         }
     }
+
+    pub fn open(path: &str) -> image::ImageResult<Self> {
+        Ok(Self {
+            image: Canvas::open(path)?,
+        })
+    }
 }
 
 impl Texture for ImageTexture {
@@ -144,6 +150,10 @@ pub struct Atlas {
     pub attenuation: Option<Box<dyn Texture>>,
     pub emission: Option<Box<dyn Texture>>,
     pub normal: Option<Box<dyn Texture>>,
+    // scales the roughness of microfacet materials by its red channel
+    pub roughness: Option<Box<dyn Texture>>,
+    // scales the absorption of dielectrics per channel, or gives it if they have none
+    pub absorption: Option<Box<dyn Texture>>,
 }
 
 impl Atlas {
@@ -167,6 +177,16 @@ impl Atlas {
         self
     }
 
+    pub fn set_roughness<T: Texture + 'static>(mut self, texture: T) -> Self {
+        self.roughness = Some(Box::new(texture));
+        self
+    }
+
+    pub fn set_absorption<T: Texture + 'static>(mut self, texture: T) -> Self {
+        self.absorption = Some(Box::new(texture));
+        self
+    }
+
     pub fn should_render(&self, hit_info: &HitInfo) -> bool {
         self.transparency
             .as_ref()
@@ -179,6 +199,16 @@ impl Atlas {
             .map_or(Color::WHITE, |t| t.value(hit_info))
     }
 
+    pub fn get_roughness(&self, hit_info: &HitInfo) -> f64 {
+        self.roughness.as_ref().map_or(1.0, |t| t.value(hit_info).r)
+    }
+
+    pub fn get_absorption(&self, hit_info: &HitInfo) -> Color {
+        self.absorption
+            .as_ref()
+            .map_or(Color::WHITE, |t| t.value(hit_info))
+    }
+
     pub fn get_emission(&self, hit_info: &HitInfo) -> Color {
         self.emission
             .as_ref()
@@ -0,0 +1,148 @@
+use std::str::FromStr;
+
+use image::Rgb;
+
+use crate::color::Color;
+use crate::interval::Interval;
+
+// maps scene radiance into [0, 1] before the transfer function is applied
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Operator {
+    #[default]
+    Clamp,
+    Reinhard,
+    Aces,
+    Hable,
+}
+
+// encodes the mapped linear value for display
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Transfer {
+    // square root, which is what Rgb<u8>::from(Color) does
+    #[default]
+    Gamma,
+    Srgb,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ToneMap {
+    pub exposure: f64, // in stops (EV). the radiance is scaled by 2^exposure
+    pub operator: Operator,
+    pub transfer: Transfer,
+}
+
+impl Operator {
+    fn map(self, x: f64) -> f64 {
+        match self {
+            Operator::Clamp => x,
+            Operator::Reinhard => x / (1.0 + x),
+            // Krzysztof Narkowicz's fit of the ACES filmic curve
+            Operator::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+            // John Hable's Uncharted 2 curve, normalized by the white point
+            Operator::Hable => {
+                const WHITE: f64 = 11.2;
+                const EXPOSURE_BIAS: f64 = 2.0;
+                Self::hable(x * EXPOSURE_BIAS) / Self::hable(WHITE)
+            }
+        }
+    }
+
+    fn hable(x: f64) -> f64 {
+        const A: f64 = 0.15; // shoulder strength
+        const B: f64 = 0.50; // linear strength
+        const C: f64 = 0.10; // linear angle
+        const D: f64 = 0.20; // toe strength
+        const E: f64 = 0.02; // toe numerator
+        const F: f64 = 0.30; // toe denominator
+        (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+    }
+}
+
+impl FromStr for Operator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clamp" => Ok(Operator::Clamp),
+            "reinhard" => Ok(Operator::Reinhard),
+            "aces" => Ok(Operator::Aces),
+            "hable" => Ok(Operator::Hable),
+            _ => Err(format!("unknown tone mapping operator \"{}\"", s)),
+        }
+    }
+}
+
+impl Transfer {
+    fn encode(self, x: f64) -> f64 {
+        match self {
+            Transfer::Gamma => x.sqrt(),
+            Transfer::Srgb => {
+                if x <= 0.0031308 {
+                    12.92 * x
+                } else {
+                    1.055 * x.powf(1.0 / 2.4) - 0.055
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Transfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gamma" => Ok(Transfer::Gamma),
+            "srgb" => Ok(Transfer::Srgb),
+            _ => Err(format!("unknown transfer function \"{}\"", s)),
+        }
+    }
+}
+
+impl ToneMap {
+    pub fn new(exposure: f64, operator: Operator, transfer: Transfer) -> Self {
+        Self {
+            exposure,
+            operator,
+            transfer,
+        }
+    }
+
+    // radiance to display-encoded values in [0, 1]
+    pub fn apply(&self, color: Color) -> Color {
+        let scale = self.exposure.exp2();
+        let channel = |x: f64| {
+            let mapped = self.operator.map((x * scale).max(0.0));
+            self.transfer.encode(Interval::UNIT.clamp(mapped))
+        };
+        Color::new(channel(color.r), channel(color.g), channel(color.b))
+    }
+
+    pub fn to_rgb(&self, color: Color) -> Rgb<u8> {
+        let color = self.apply(color);
+        Rgb([
+            (color.r * 256.0) as u8,
+            (color.g * 256.0) as u8,
+            (color.b * 256.0) as u8,
+        ])
+    }
+}
+
+#[test]
+fn test_tone_map() {
+    // the default matches the plain conversion
+    let color = Color::new(0.25, 2.0, -1.0);
+    assert_eq!(ToneMap::default().to_rgb(color), Rgb::<u8>::from(color));
+    let srgb = ToneMap::new(0.0, Operator::Clamp, Transfer::Srgb);
+    assert_eq!(srgb.to_rgb(Color::WHITE), Rgb([255, 255, 255]));
+    assert!((srgb.apply(Color::gray(0.5)).r - 0.7353569).abs() < 1e-6);
+    for operator in [Operator::Reinhard, Operator::Aces, Operator::Hable] {
+        let tone_map = ToneMap::new(1.0, operator, Transfer::Srgb);
+        let mut last = 0.0;
+        for x in [0.01, 0.1, 0.5, 1.0, 4.0, 20.0] {
+            let y = tone_map.apply(Color::gray(x)).r;
+            assert!(y >= last && y <= 1.0);
+            last = y;
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use std::ops::Mul;
+
+use crate::aabb::Aabb;
 use crate::vec3::Vec3;
 
 #[derive(Clone, Copy, Debug)]
@@ -93,4 +96,98 @@ impl Transform {
         ret.matrix[1][1] = c;
         ret
     }
+
+    pub fn transpose(&self) -> Self {
+        let mut ret = Self::default();
+        for i in 0..4 {
+            for j in 0..4 {
+                ret.matrix[i][j] = self.matrix[j][i];
+            }
+        }
+        ret
+    }
+
+    // gauss-jordan elimination with partial pivoting. panics if the matrix is singular
+    pub fn inverse(&self) -> Self {
+        let mut a = self.matrix;
+        let mut ret = Self::default();
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+                .unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                panic!("Transform::inverse() called on a singular matrix");
+            }
+            a.swap(col, pivot);
+            ret.matrix.swap(col, pivot);
+            let inv_pivot = 1.0 / a[col][col];
+            a[col].iter_mut().for_each(|x| *x *= inv_pivot);
+            ret.matrix[col].iter_mut().for_each(|x| *x *= inv_pivot);
+            let (pivot_row, pivot_inv_row) = (a[col], ret.matrix[col]);
+            for (row, (a_row, inv_row)) in a.iter_mut().zip(ret.matrix.iter_mut()).enumerate() {
+                if row != col {
+                    let factor = a_row[col];
+                    for j in 0..4 {
+                        a_row[j] -= factor * pivot_row[j];
+                        inv_row[j] -= factor * pivot_inv_row[j];
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    // the inverse transpose, which maps normals correctly under non-uniform scaling.
+    // apply it with direction() and normalize the result
+    pub fn normal_matrix(&self) -> Self {
+        self.inverse().transpose()
+    }
+
+    // transform all 8 corners and take their bounds
+    pub fn bounding_box(&self, aabb: Aabb) -> Aabb {
+        if aabb.x.empty() || aabb.y.empty() || aabb.z.empty() {
+            return aabb;
+        }
+        let mut ret = Aabb::default();
+        for i in 0..2 {
+            for j in 0..2 {
+                for k in 0..2 {
+                    let corner = self.pos(Vec3::new(aabb.x[i], aabb.y[j], aabb.z[k]));
+                    ret = ret.union(Aabb::from_vec3(corner, corner));
+                }
+            }
+        }
+        ret
+    }
+}
+
+impl Mul for Transform {
+    type Output = Self;
+
+    // the right hand side is applied first
+    fn mul(self, rhs: Self) -> Self {
+        let mut ret = Self {
+            matrix: [[0.0; 4]; 4],
+        };
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    ret.matrix[i][j] += self.matrix[i][k] * rhs.matrix[k][j];
+                }
+            }
+        }
+        ret
+    }
+}
+
+#[test]
+fn test_transform_inverse() {
+    let transform = Transform::translate(Vec3::new(1.0, -2.0, 3.0))
+        * Transform::rotate_y(30.0)
+        * Transform::scale(Vec3::new(2.0, 0.5, 4.0));
+    let p = Vec3::new(0.3, 0.7, -1.1);
+    let back = transform.inverse().pos(transform.pos(p));
+    assert!((back - p).length() < 1e-9);
+    let identity = transform * transform.inverse();
+    assert!((identity.pos(p) - p).length() < 1e-9);
 }
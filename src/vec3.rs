@@ -1,4 +1,4 @@
-use rand::Rng;
+use crate::sampler::Sampler;
 use std::f64::consts::PI;
 use std::ops::{
     Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, MulAssign, Neg, Sub, SubAssign,
@@ -32,34 +32,31 @@ impl Vec3 {
         *self / self.length()
     }
 
-    pub fn random(min: f64, max: f64) -> Vec3 {
-        let mut rng = rand::thread_rng();
-        Vec3::new(
-            rng.gen_range(min..max),
-            rng.gen_range(min..max),
-            rng.gen_range(min..max),
-        )
+    pub fn random(min: f64, max: f64, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut value = || min + (max - min) * sampler.get_1d();
+        Vec3::new(value(), value(), value())
     }
 
-    pub fn random_in_cube() -> Vec3 {
-        Vec3::random(-1.0, 1.0)
+    pub fn random_in_cube(sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::random(-1.0, 1.0, sampler)
     }
 
-    pub fn random_in_sphere() -> Vec3 {
-        loop {
-            let p = Vec3::random_in_cube();
-            if p.length_squared() < 1.0 {
-                return p;
-            }
-        }
+    // the random functions below map a fixed number of sampler dimensions instead of rejecting,
+    // so that each sample consumes the same dimensions
+    pub fn random_in_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::random_unit_vector(sampler) * sampler.get_1d().cbrt()
     }
 
-    pub fn random_unit_vector() -> Vec3 {
-        Vec3::random_in_sphere().normalize()
+    pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.get_2d();
+        let z = 1.0 - 2.0 * r1;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * r2;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
     }
 
-    pub fn random_unit_on_hemisphere(normal: Vec3) -> Vec3 {
-        let unit = Vec3::random_unit_vector();
+    pub fn random_unit_on_hemisphere(normal: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let unit = Vec3::random_unit_vector(sampler);
         if unit.dot(normal) > 0.0 {
             unit
         } else {
@@ -67,24 +64,21 @@ impl Vec3 {
         }
     }
 
-    pub fn random_in_unit_disk() -> Vec3 {
-        let mut rng = rand::thread_rng();
-        loop {
-            let p = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
-            if p.length_squared() < 1.0 {
-                return p;
-            }
-        }
+    pub fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.get_2d();
+        let r = r1.sqrt();
+        let phi = 2.0 * PI * r2;
+        Vec3::new(r * phi.cos(), r * phi.sin(), 0.0)
     }
 
-    pub fn random_planar_unit_vector() -> Vec3 {
-        Vec3::random_in_unit_disk().normalize()
+    pub fn random_planar_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+        let phi = 2.0 * PI * sampler.get_1d();
+        Vec3::new(phi.cos(), phi.sin(), 0.0)
     }
 
     // return a random vector in the unit hemisphere with cosine distribution
-    pub fn random_cosine_direction() -> Vec3 {
-        let r1 = rand::random::<f64>();
-        let r2 = rand::random::<f64>();
+    pub fn random_cosine_direction(sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.get_2d();
         let phi = 2.0 * PI * r1;
         let x = phi.cos() * r2.sqrt();
         let y = phi.sin() * r2.sqrt();
@@ -97,9 +91,8 @@ impl Vec3 {
         self - normal * 2.0 * self.dot(normal)
     }
 
-    pub fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
-        let r1 = rand::random::<f64>();
-        let r2 = rand::random::<f64>();
+    pub fn random_to_sphere(radius: f64, distance_squared: f64, sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.get_2d();
         let z = 1.0 + r2 * ((1.0 - radius * radius / distance_squared).sqrt() - 1.0);
         let phi = 2.0 * PI * r1;
         let x = phi.cos() * (1.0 - z * z).sqrt();